@@ -0,0 +1,36 @@
+//! The hardware-free modules of `src/audio`, built from the firmware's own
+//! sources. Their `#[cfg(test)]` modules are the tests.
+//!
+//! What only the hardware side of the firmware calls is dead code here.
+#![allow(dead_code)]
+
+#[path = "../../src/audio/ab_repeat.rs"]
+pub mod ab_repeat;
+#[path = "../../src/audio/dsp/mod.rs"]
+pub mod dsp;
+#[path = "../../src/audio/fade.rs"]
+pub mod fade;
+#[path = "../../src/audio/gain.rs"]
+pub mod gain;
+#[path = "../../src/audio/loudness.rs"]
+pub mod loudness;
+#[path = "../../src/audio/meter.rs"]
+pub mod meter;
+#[path = "../../src/audio/pcm.rs"]
+pub mod pcm;
+#[path = "../../src/audio/player/mod.rs"]
+pub mod player;
+#[path = "../../src/audio/playlist.rs"]
+pub mod playlist;
+#[path = "../../src/audio/replaygain.rs"]
+pub mod replaygain;
+#[path = "../../src/audio/resume.rs"]
+pub mod resume;
+#[path = "../../src/audio/spectrum.rs"]
+pub mod spectrum;
+#[path = "../../src/audio/status.rs"]
+pub mod status;
+#[path = "../../src/audio/volume.rs"]
+pub mod volume;
+
+pub use player::{FileInfo, PLAYER_COMMANDS, PLAYER_QUEUE, QUEUE_RECEIVERS, QueueReceiver};
@@ -0,0 +1,197 @@
+//! A-B repeat of a region of the current track.
+//!
+//! Positions are decoder PCM frames, so the loop is sample accurate: reads are
+//! cut short at B and the decoder seeks back to A. A short [`Fade`] out before
+//! B and back in after A hides the seam.
+
+use crate::audio::fade::{Fade, FadeSettings};
+use crate::audio::pcm::AudioBuffer;
+
+/// Fade lengths either side of the seam.
+pub const SEAM_FADE: FadeSettings = FadeSettings {
+    fade_in_ms: 5,
+    fade_out_ms: 5,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AbRepeatCommand {
+    /// Marks A at the position currently being heard.
+    MarkA,
+    /// Marks B at the position currently being heard and starts looping.
+    MarkB,
+    /// Loops `a..b`, in decoder PCM frames.
+    Set {
+        a: u64,
+        b: u64,
+    },
+    /// How many more times to jump back to A, `None` loops until cleared.
+    SetRepeats(Option<u32>),
+    Clear,
+}
+
+pub struct AbRepeat {
+    a: Option<u64>,
+    b: Option<u64>,
+    repeats: Option<u32>,
+    fade: Fade,
+}
+
+impl AbRepeat {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            a: None,
+            b: None,
+            repeats: None,
+            fade: Fade::new(sample_rate, SEAM_FADE),
+        }
+    }
+
+    pub fn region(&self) -> Option<(u64, u64)> {
+        self.a.zip(self.b)
+    }
+
+    pub fn repeats(&self) -> Option<u32> {
+        self.repeats
+    }
+
+    /// `audible_position` is the frame coming out of the speaker right now,
+    /// which lags the decoder by the DSP and DMA latency.
+    pub fn handle_command(&mut self, command: AbRepeatCommand, audible_position: u64) {
+        match command {
+            AbRepeatCommand::MarkA => {
+                self.a = Some(audible_position);
+                self.b = None;
+            }
+            AbRepeatCommand::MarkB => {
+                let a = *self.a.get_or_insert(0);
+                self.b = (audible_position > a).then_some(audible_position);
+            }
+            AbRepeatCommand::Set { a, b } => {
+                let valid = a < b;
+                self.a = valid.then_some(a);
+                self.b = valid.then_some(b);
+            }
+            AbRepeatCommand::SetRepeats(repeats) => self.repeats = repeats,
+            AbRepeatCommand::Clear => self.clear(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.a = None;
+        self.b = None;
+        self.repeats = None;
+    }
+
+    fn will_loop(&self) -> bool {
+        self.region().is_some() && self.repeats != Some(0)
+    }
+
+    /// Where the decoder has to seek to before reading from `position`, if
+    /// it has reached B.
+    pub fn loop_target(&mut self, position: u64) -> Option<u64> {
+        let (a, b) = self.region()?;
+        if position < b {
+            return None;
+        }
+        if !self.will_loop() {
+            // Out of repeats, play on past B.
+            self.clear();
+            return None;
+        }
+        if let Some(repeats) = self.repeats.as_mut() {
+            *repeats -= 1;
+        }
+        self.fade.mute();
+        Some(a)
+    }
+
+    /// Caps a read starting at `position` so it stops exactly at B.
+    pub fn frames_to_read(&self, position: u64, frames: u64) -> u64 {
+        match self.region() {
+            Some((_, b)) if self.will_loop() && position < b => frames.min(b - position),
+            _ => frames,
+        }
+    }
+
+    /// Applies the seam fades to PCM that starts at `position`.
+    pub fn apply_fades(&mut self, buffer: &mut AudioBuffer<'_, i16>, position: u64) {
+        // Ramps back up after a jump to A, or after the region is cleared mid fade.
+        self.fade.fade_in();
+        let fade_out_from = match self.region() {
+            Some((_, b)) if self.will_loop() => {
+                b.saturating_sub(self.fade.fade_out_frames() as u64)
+            }
+            _ => u64::MAX,
+        };
+        let frames = buffer.frames();
+        let split = fade_out_from.saturating_sub(position).min(frames as u64) as usize;
+        let format = buffer.format();
+        let channels = buffer.channels();
+        let (before, after) = buffer.samples_mut().split_at_mut(split * channels);
+        self.fade
+            .apply(&mut AudioBuffer::new(before, split, format));
+        if split < frames {
+            self.fade.fade_out();
+            self.fade
+                .apply(&mut AudioBuffer::new(after, frames - split, format));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::pcm::StreamFormat;
+    use alloc::vec;
+
+    const RATE: u32 = 1000;
+
+    fn looping() -> AbRepeat {
+        let mut ab_repeat = AbRepeat::new(RATE);
+        ab_repeat.handle_command(AbRepeatCommand::Set { a: 100, b: 200 }, 0);
+        ab_repeat
+    }
+
+    fn block(samples: &mut [i16]) -> AudioBuffer<'_, i16> {
+        let format = StreamFormat {
+            sample_rate: RATE,
+            ..StreamFormat::default()
+        };
+        let frames = samples.len() / format.channels();
+        AudioBuffer::new(samples, frames, format)
+    }
+
+    #[test]
+    fn fades_out_into_b() {
+        let mut ab_repeat = looping();
+        let mut samples = vec![1000i16; 2 * 100];
+        ab_repeat.apply_fades(&mut block(&mut samples), 100);
+        assert!(samples[..2 * 95].iter().all(|&s| s == 1000));
+        assert!(samples[2 * 95..].windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(samples[2 * 99], 0);
+    }
+
+    #[test]
+    fn fades_back_in_after_a() {
+        let mut ab_repeat = looping();
+        assert_eq!(ab_repeat.loop_target(200), Some(100));
+        let mut samples = vec![1000i16; 2 * 10];
+        ab_repeat.apply_fades(&mut block(&mut samples), 100);
+        assert!(samples[0] < 1000);
+        assert!(samples.windows(2).all(|w| w[1] >= w[0]));
+        assert_eq!(samples[2 * 9], 1000);
+    }
+
+    #[test]
+    fn clearing_mid_fade_ramps_back_up() {
+        let mut ab_repeat = looping();
+        let mut samples = vec![1000i16; 2 * 98];
+        ab_repeat.apply_fades(&mut block(&mut samples), 100);
+        assert!(samples[2 * 97] < 1000);
+        ab_repeat.handle_command(AbRepeatCommand::Clear, 0);
+        let mut samples = vec![1000i16; 2 * 10];
+        ab_repeat.apply_fades(&mut block(&mut samples), 198);
+        assert_eq!(ab_repeat.loop_target(200), None);
+        assert_eq!(samples[2 * 9], 1000);
+    }
+}
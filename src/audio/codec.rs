@@ -7,9 +7,14 @@ pub mod codec {
     use heapless::{String, Vec};
 
     use crate::audio::dr_flac_bindings::{
-        self, drflac, drflac_allocation_callbacks, drflac_int16, drflac_open_memory,
-        drflac_streaminfo,
+        self, DRFLAC_METADATA_BLOCK_TYPE_STREAMINFO, DRFLAC_METADATA_BLOCK_TYPE_VORBIS_COMMENT,
+        drflac, drflac_allocation_callbacks, drflac_init_vorbis_comment_iterator, drflac_int16,
+        drflac_int32, drflac_metadata, drflac_next_vorbis_comment,
+        drflac_open_memory_with_metadata, drflac_streaminfo, drflac_vorbis_comment_iterator,
     };
+    use crate::audio::pcm::{AudioBuffer, ChannelLayout, Sample, SampleFormat, StreamFormat};
+    use crate::audio::replaygain::ReplayGainInfo;
+    use crate::audio::status::Codec;
 
     unsafe fn malloc_8_bytes_aligned_memory(size: usize) -> *mut u8 {
         let total_size = size + 8;
@@ -110,10 +115,82 @@ pub mod codec {
         pub stream_info: Option<drflac_streaminfo>,
         pub stream_info_size: usize,
         pub vorbis_comments_size: usize,
+        pub replay_gain: ReplayGainInfo,
         // pub picture_data: Option<Vec<u8, 2048>>,
         // pub picture_info: Option<PictureInfo<32, 64>>,
     }
 
+    impl Metadata {
+        /// Takes a raw Vorbis comment of the form `KEY=value`.
+        fn parse_vorbis_comment(&mut self, comment: &[u8]) {
+            let Some((key, value)) = core::str::from_utf8(comment)
+                .ok()
+                .and_then(|comment| comment.split_once('='))
+            else {
+                return;
+            };
+            if self.replay_gain.parse_tag(key, value) {
+                return;
+            }
+            let field = if key.eq_ignore_ascii_case("TITLE") {
+                &mut self.title_name
+            } else if key.eq_ignore_ascii_case("ALBUM") {
+                &mut self.album_name
+            } else if key.eq_ignore_ascii_case("ALBUMARTIST") {
+                &mut self.album_artist
+            } else {
+                return;
+            };
+            field.clear();
+            for c in value.chars() {
+                if field.push(c).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Called by dr_flac for every metadata block while the stream is being opened.
+    unsafe extern "C" fn on_meta_read(
+        pUserData: *mut cty::c_void,
+        pMetadata: *mut drflac_metadata,
+    ) {
+        let (metadata, block) = unsafe { (&mut *(pUserData as *mut Metadata), &*pMetadata) };
+        match block.type_ {
+            DRFLAC_METADATA_BLOCK_TYPE_STREAMINFO => {
+                metadata.stream_info = Some(unsafe { block.data.streaminfo });
+                metadata.stream_info_size = block.rawDataSize as usize;
+            }
+            DRFLAC_METADATA_BLOCK_TYPE_VORBIS_COMMENT => {
+                metadata.vorbis_comments_size = block.rawDataSize as usize;
+                let vorbis_comment = unsafe { block.data.vorbis_comment };
+                let mut iter = drflac_vorbis_comment_iterator {
+                    countRemaining: 0,
+                    pRunningData: ptr::null(),
+                };
+                unsafe {
+                    drflac_init_vorbis_comment_iterator(
+                        &mut iter,
+                        vorbis_comment.commentCount,
+                        vorbis_comment.pComments,
+                    );
+                }
+                loop {
+                    let mut comment_len = 0;
+                    let comment =
+                        unsafe { drflac_next_vorbis_comment(&mut iter, &mut comment_len) };
+                    if comment.is_null() {
+                        break;
+                    }
+                    metadata.parse_vorbis_comment(unsafe {
+                        core::slice::from_raw_parts(comment as *const u8, comment_len as usize)
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub struct MediaContainer {
         pub filename: &'static str,
         pub metadata: Metadata,
@@ -138,13 +215,16 @@ pub mod codec {
                 // "aac" => self::aac,
                 // "mp3"   => self::mp3,
                 "flac" => {
-                    // this actually can happen only once, instead of happening for every fileopen
-                    // unsafe { drflac_open_memory_with_metadata(p_data_const,p_data_const.len(),Some(on_meta_read),cty::NULL ,pAllocCallbacks); };
+                    let mut metadata = Metadata::default();
+                    // dr_flac skips a leading ID3v2 tag, so gain tags in there are read separately.
+                    metadata.replay_gain.parse_id3v2(p_data_const);
 
                     unsafe {
-                        let decoder_obj = drflac_open_memory(
+                        let decoder_obj = drflac_open_memory_with_metadata(
                             p_data_const.as_ptr() as *const c_void,
                             p_data_const.len(),
+                            Some(on_meta_read),
+                            &mut metadata as *mut Metadata as *mut c_void,
                             &drflac_allocation_callbacks {
                                 pUserData: ptr::null::<u8>() as *mut c_void,
                                 onMalloc: Some(my_malloc),
@@ -156,7 +236,7 @@ pub mod codec {
                         (*decoder_obj).totalPCMFrameCount};
                         Self::FLAC(MediaContainer {
                             filename: filename,
-                            metadata: Metadata::default(),
+                            metadata,
                             decoder_obj,
                         })
                     }
@@ -164,32 +244,132 @@ pub mod codec {
                 _ => panic!("what!"),
             }
         }
-        pub fn get_pcm_samples(
+        pub fn metadata(&self) -> &Metadata {
+            match self {
+                Decoder::FLAC(media_container) => &media_container.metadata,
+            }
+        }
+        pub fn sample_rate(&self) -> u32 {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    (*media_container.decoder_obj).sampleRate
+                },
+            }
+        }
+        pub fn channels(&self) -> usize {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    (*media_container.decoder_obj).channels as usize
+                },
+            }
+        }
+        pub fn layout(&self) -> ChannelLayout {
+            // dr_flac refuses to open streams outside FLAC's 1 to 8 channels.
+            ChannelLayout::from_channels(self.channels()).unwrap()
+        }
+        pub fn stream_format(&self) -> StreamFormat {
+            StreamFormat {
+                sample_rate: self.sample_rate(),
+                layout: self.layout(),
+            }
+        }
+        pub fn codec(&self) -> Codec {
+            match self {
+                Decoder::FLAC(_) => Codec::Flac,
+            }
+        }
+        pub fn bits_per_sample(&self) -> u8 {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    (*media_container.decoder_obj).bitsPerSample
+                },
+            }
+        }
+        pub fn total_pcm_frames(&self) -> u64 {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    (*media_container.decoder_obj).totalPCMFrameCount
+                },
+            }
+        }
+        pub fn current_pcm_frame(&self) -> u64 {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    (*media_container.decoder_obj).currentPCMFrame
+                },
+            }
+        }
+        /// Returns false if the decoder could not seek.
+        pub fn seek_to_pcm_frame(&mut self, pcm_frame: u64) -> bool {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    dr_flac_bindings::drflac_seek_to_pcm_frame(
+                        media_container.decoder_obj,
+                        pcm_frame,
+                    ) != 0
+                },
+            }
+        }
+        /// Reads up to `max_frames` into `buffer`, converted to its sample
+        /// type, and sets its contents to what was read.
+        pub fn read_frames<S: Sample>(
             &mut self,
-            frames_to_read: u64,
-            pcm_frames: &mut [i16],
+            buffer: &mut AudioBuffer<'_, S>,
+            max_frames: u64,
         ) -> DecoderResult {
+            let format = self.stream_format();
+            let samples = buffer.buffer_mut();
+            let frames_to_read = max_frames.min((samples.len() / format.channels()) as u64);
+            let out = samples.as_mut_ptr();
             match self {
                 Decoder::FLAC(current_metadata_container) => unsafe {
-                    let frames_read = dr_flac_bindings::drflac_read_pcm_frames_s16(
-                        current_metadata_container.decoder_obj,
-                        frames_to_read,
-                        pcm_frames.as_mut_ptr() as *mut drflac_int16,
-                    );
-                    info! {"pBuffOut is zero?:{}", pcm_frames.iter().all(|&x| x == 0)};
+                    let decoder_obj = current_metadata_container.decoder_obj;
+                    // `Sample` guarantees `S` is laid out as `S::FORMAT` says.
+                    let frames_read = match S::FORMAT {
+                        SampleFormat::S16 => dr_flac_bindings::drflac_read_pcm_frames_s16(
+                            decoder_obj,
+                            frames_to_read,
+                            out as *mut drflac_int16,
+                        ),
+                        SampleFormat::S24In32 | SampleFormat::S32 => {
+                            dr_flac_bindings::drflac_read_pcm_frames_s32(
+                                decoder_obj,
+                                frames_to_read,
+                                out as *mut drflac_int32,
+                            )
+                        }
+                        SampleFormat::F32 => dr_flac_bindings::drflac_read_pcm_frames_f32(
+                            decoder_obj,
+                            frames_to_read,
+                            out as *mut f32,
+                        ),
+                    };
+                    buffer.set_contents(frames_read as usize, format);
+                    if S::FORMAT == SampleFormat::S24In32 {
+                        // dr_flac only hands out full scale 32 bit samples.
+                        for sample in buffer.samples_mut() {
+                            let full_scale = *(sample as *mut S as *const i32);
+                            *sample = S::from_i32_full_scale(full_scale);
+                        }
+                    }
 
-                    // info! {"pBuffOut :{}", pcm_frames};
                     DecoderResult {
                         framesRead: frames_read,
-                        currentPCMFrameIdx: current_metadata_container
-                            .decoder_obj
-                            .as_ref()
-                            .unwrap()
-                            .currentPCMFrame,
+                        currentPCMFrameIdx: decoder_obj.as_ref().unwrap().currentPCMFrame,
                         is_eof: false,
                     }
                 },
             }
         }
     }
+
+    impl Drop for Decoder {
+        fn drop(&mut self) {
+            match self {
+                Decoder::FLAC(media_container) => unsafe {
+                    dr_flac_bindings::drflac_close(media_container.decoder_obj);
+                },
+            }
+        }
+    }
 }
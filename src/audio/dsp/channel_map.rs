@@ -0,0 +1,150 @@
+//! Maps whatever layout the stream has onto the layout of the sink.
+//!
+//! Input channels are placed by the FLAC/WAVE default channel order for their
+//! count. A stereo sink gets the usual ITU downmix: centre and surrounds go to
+//! their side at -3 dB, a back centre to both at -6 dB and the LFE is left out.
+//! Each output is scaled so that full scale on every input cannot clip it.
+//! Mono is copied to every output channel.
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::pcm::{ChannelLayout, Speaker};
+
+/// Channels beyond this have no defined position and are left out.
+pub const MAX_MAPPED_CHANNELS: usize = 8;
+
+const MINUS_3_DB: f32 = core::f32::consts::FRAC_1_SQRT_2;
+const MINUS_6_DB: f32 = 0.5;
+
+/// How much of `speaker` goes into the left output of a stereo downmix, the
+/// right output is the mirror image.
+fn left_weight(speaker: Speaker) -> f32 {
+    match speaker {
+        Speaker::Left => 1.0,
+        Speaker::Center | Speaker::SurroundLeft => MINUS_3_DB,
+        Speaker::BackCenter => MINUS_6_DB,
+        Speaker::Right | Speaker::Lfe | Speaker::SurroundRight => 0.0,
+    }
+}
+
+fn mirror(speaker: Speaker) -> Speaker {
+    match speaker {
+        Speaker::Left => Speaker::Right,
+        Speaker::Right => Speaker::Left,
+        Speaker::SurroundLeft => Speaker::SurroundRight,
+        Speaker::SurroundRight => Speaker::SurroundLeft,
+        speaker => speaker,
+    }
+}
+
+pub struct ChannelMap {
+    layout: ChannelLayout,
+    input_channels: usize,
+    /// `weights[output][input]`.
+    weights: [[f32; MAX_MAPPED_CHANNELS]; MAX_MAPPED_CHANNELS],
+}
+
+impl ChannelMap {
+    /// Maps every stream onto `layout`.
+    pub fn new(layout: ChannelLayout) -> Self {
+        let mut channel_map = Self {
+            layout,
+            input_channels: layout.channels(),
+            weights: [[0.0; MAX_MAPPED_CHANNELS]; MAX_MAPPED_CHANNELS],
+        };
+        channel_map.design();
+        channel_map
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    fn is_passthrough(&self) -> bool {
+        self.input_channels == self.layout.channels()
+    }
+
+    fn design(&mut self) {
+        self.weights = [[0.0; MAX_MAPPED_CHANNELS]; MAX_MAPPED_CHANNELS];
+        let inputs = self.input_channels.min(MAX_MAPPED_CHANNELS);
+        let outputs = self.layout.channels().min(MAX_MAPPED_CHANNELS);
+        let speakers = ChannelLayout::from_channels(self.input_channels)
+            .map_or(&[][..], ChannelLayout::speakers);
+        let speakers = &speakers[..inputs];
+        for (output, row) in self.weights[..outputs].iter_mut().enumerate() {
+            for (input, weight) in row[..inputs].iter_mut().enumerate() {
+                let speaker = speakers[input];
+                *weight = match (inputs, outputs) {
+                    (1, _) => 1.0,
+                    (_, 1) if speaker == Speaker::Lfe => 0.0,
+                    (_, 1) => 1.0,
+                    (_, 2) if output == 0 => left_weight(speaker),
+                    (_, 2) => left_weight(mirror(speaker)),
+                    // No downmix defined for wider sinks, channels go straight through.
+                    _ if input == output => 1.0,
+                    _ => 0.0,
+                };
+            }
+            let total: f32 = row.iter().sum();
+            if total > 1.0 {
+                row.iter_mut().for_each(|weight| *weight /= total);
+            }
+        }
+    }
+}
+
+impl Processor for ChannelMap {
+    fn configure(&mut self, format: StreamFormat) {
+        self.input_channels = format.channels();
+        self.design();
+    }
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        let output_format = self.output_format(block.format());
+        // Fewer frames fit once the layout is wider, the caller leaves room
+        // with `AudioBuffer::limit_frames`.
+        block.set_contents(block.frames(), output_format);
+        if self.is_passthrough() {
+            return;
+        }
+        let inputs = self.input_channels;
+        let outputs = self.layout.channels();
+        let frames = block.frames();
+        let buffer = block.buffer_mut();
+        let mut map = |frame: usize| {
+            let mut input = [0.0; MAX_MAPPED_CHANNELS];
+            let mapped = inputs.min(MAX_MAPPED_CHANNELS);
+            for (value, &sample) in input
+                .iter_mut()
+                .zip(&buffer[frame * inputs..frame * inputs + mapped])
+            {
+                *value = sample as f32;
+            }
+            for output in 0..outputs {
+                let weights = self
+                    .weights
+                    .get(output)
+                    .map_or(&[0.0; MAX_MAPPED_CHANNELS], |weights| weights);
+                let mixed: f32 = weights.iter().zip(&input).map(|(w, x)| w * x).sum();
+                buffer[frame * outputs + output] =
+                    libm::roundf(mixed).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        };
+        // Narrowing writes behind what is still to be read, widening ahead of
+        // it, so the frames are walked in the order that never overwrites one
+        // before it is read.
+        if outputs < inputs {
+            (0..frames).for_each(&mut map);
+        } else {
+            (0..frames).rev().for_each(&mut map);
+        }
+    }
+
+    fn output_format(&self, input: StreamFormat) -> StreamFormat {
+        StreamFormat {
+            layout: self.layout,
+            ..input
+        }
+    }
+}
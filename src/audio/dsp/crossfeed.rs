@@ -0,0 +1,136 @@
+//! Bauer stereophonic-to-binaural crossfeed for headphone listening.
+//!
+//! Same topology as bs2b: each output channel is its own channel through a
+//! first order high shelf plus the opposite channel through a first order low
+//! pass, which mimics the head shadow of listening to a pair of speakers.
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::round_and_saturate;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CrossfeedStrength {
+    /// Jan Meier's circuit, 650 Hz / 9.5 dB.
+    Mild,
+    /// Chu Moy's circuit, 700 Hz / 6 dB.
+    #[default]
+    Medium,
+    /// bs2b default, 700 Hz / 4.5 dB.
+    Strong,
+}
+
+impl CrossfeedStrength {
+    /// Low pass cutoff in Hz and feed level in dB.
+    fn parameters(self) -> (f32, f32) {
+        match self {
+            CrossfeedStrength::Mild => (650.0, 9.5),
+            CrossfeedStrength::Medium => (700.0, 6.0),
+            CrossfeedStrength::Strong => (700.0, 4.5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    low_pass: f32,
+    high_shelf: f32,
+    last_input: f32,
+}
+
+pub struct Crossfeed {
+    strength: CrossfeedStrength,
+    sample_rate: u32,
+    channels: usize,
+    low_pass_a0: f32,
+    low_pass_b1: f32,
+    high_shelf_a0: f32,
+    high_shelf_a1: f32,
+    high_shelf_b1: f32,
+    state: [ChannelState; 2],
+    clipped: u32,
+}
+
+impl Crossfeed {
+    pub fn new(strength: CrossfeedStrength) -> Self {
+        let mut crossfeed = Self {
+            strength,
+            sample_rate: 48_000,
+            channels: 2,
+            low_pass_a0: 0.0,
+            low_pass_b1: 0.0,
+            high_shelf_a0: 1.0,
+            high_shelf_a1: 0.0,
+            high_shelf_b1: 0.0,
+            state: [ChannelState::default(); 2],
+            clipped: 0,
+        };
+        crossfeed.design();
+        crossfeed
+    }
+
+    pub fn strength(&self) -> CrossfeedStrength {
+        self.strength
+    }
+
+    pub fn set_strength(&mut self, strength: CrossfeedStrength) {
+        self.strength = strength;
+        self.design();
+    }
+
+    fn design(&mut self) {
+        let (cutoff_hz, feed_db) = self.strength.parameters();
+        let fs = self.sample_rate as f32;
+        let low_gain_db = feed_db * -5.0 / 6.0 - 3.0;
+        let high_gain_db = feed_db / 6.0 - 3.0;
+        let low_gain = libm::powf(10.0, low_gain_db / 20.0);
+        let high_gain = 1.0 - libm::powf(10.0, high_gain_db / 20.0);
+        let high_cutoff_hz =
+            cutoff_hz * libm::exp2f((low_gain_db - 20.0 * libm::log10f(high_gain)) / 12.0);
+
+        let x = libm::expf(-2.0 * core::f32::consts::PI * cutoff_hz / fs);
+        self.low_pass_b1 = x;
+        self.low_pass_a0 = low_gain * (1.0 - x);
+
+        let x = libm::expf(-2.0 * core::f32::consts::PI * high_cutoff_hz / fs);
+        self.high_shelf_b1 = x;
+        self.high_shelf_a0 = 1.0 - high_gain * (1.0 - x);
+        self.high_shelf_a1 = -x;
+    }
+}
+
+impl Processor for Crossfeed {
+    fn configure(&mut self, format: StreamFormat) {
+        self.sample_rate = format.sample_rate;
+        self.channels = format.channels();
+        self.design();
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.state = [ChannelState::default(); 2];
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        // Nothing to cross over on mono streams.
+        if self.channels != 2 {
+            return;
+        }
+        for frame in block.frames_iter_mut() {
+            let input = [frame[0] as f32, frame[1] as f32];
+            for (state, x) in self.state.iter_mut().zip(input) {
+                state.low_pass = self.low_pass_a0 * x + self.low_pass_b1 * state.low_pass;
+                state.high_shelf = self.high_shelf_a0 * x
+                    + self.high_shelf_a1 * state.last_input
+                    + self.high_shelf_b1 * state.high_shelf;
+                state.last_input = x;
+            }
+            let left = self.state[0].high_shelf + self.state[1].low_pass;
+            let right = self.state[1].high_shelf + self.state[0].low_pass;
+            frame[0] = round_and_saturate(left, &mut self.clipped);
+            frame[1] = round_and_saturate(right, &mut self.clipped);
+        }
+    }
+
+    fn take_clipped_samples(&mut self) -> u32 {
+        core::mem::take(&mut self.clipped)
+    }
+}
@@ -0,0 +1,260 @@
+//! Feed-forward compressor followed by a look-ahead peak limiter.
+//!
+//! The limiter delays the signal by [`LOOKAHEAD_MS`] and starts pulling the
+//! gain down as soon as a peak enters the delay line. The required gain is
+//! held for the look-ahead window and then box filtered over the same window,
+//! so by the time the peak leaves the delay line the gain is at or below what
+//! it needs and the output never goes over the ceiling.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::db_to_linear;
+
+pub const LOOKAHEAD_MS: f32 = 1.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressorSettings {
+    pub threshold_db: f32,
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub makeup_db: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DynamicsSettings {
+    /// `None` runs the limiter on its own.
+    pub compressor: Option<CompressorSettings>,
+    /// Limiter ceiling relative to full scale.
+    pub ceiling_db: f32,
+    pub release_ms: f32,
+}
+
+impl DynamicsSettings {
+    /// Just catches the overs, the headphone amp has plenty of headroom.
+    pub fn headphones() -> Self {
+        Self {
+            compressor: None,
+            ceiling_db: -0.3,
+            release_ms: 50.0,
+        }
+    }
+
+    /// Squashes loud masters so the class-D amp and the small driver stay clean.
+    pub fn speaker() -> Self {
+        Self {
+            compressor: Some(CompressorSettings {
+                threshold_db: -18.0,
+                ratio: 3.0,
+                attack_ms: 5.0,
+                release_ms: 120.0,
+                makeup_db: 4.0,
+            }),
+            ceiling_db: -1.0,
+            release_ms: 80.0,
+        }
+    }
+}
+
+impl Default for DynamicsSettings {
+    fn default() -> Self {
+        Self::headphones()
+    }
+}
+
+pub struct Dynamics {
+    settings: DynamicsSettings,
+    sample_rate: u32,
+    channels: usize,
+    /// Look-ahead window in frames, at least 1.
+    lookahead: usize,
+    /// Interleaved compressed frames waiting to be limited.
+    delay: Vec<f32>,
+    /// Smoothed limiter gain over the window, one entry per frame.
+    window: Vec<f32>,
+    window_sum: f32,
+    position: usize,
+    /// Candidates for the minimum gain over the window as (frame, gain).
+    hold: VecDeque<(u64, f32)>,
+    frame_count: u64,
+    envelope: f32,
+    reduction_db: f32,
+    ceiling: f32,
+    release_coef: f32,
+    attack_coef: f32,
+    compressor_release_coef: f32,
+}
+
+impl Dynamics {
+    pub fn new(settings: DynamicsSettings) -> Self {
+        let mut dynamics = Self {
+            settings,
+            sample_rate: 48_000,
+            channels: 2,
+            lookahead: 1,
+            delay: Vec::new(),
+            window: Vec::new(),
+            window_sum: 0.0,
+            position: 0,
+            hold: VecDeque::new(),
+            frame_count: 0,
+            envelope: 1.0,
+            reduction_db: 0.0,
+            ceiling: 0.0,
+            release_coef: 0.0,
+            attack_coef: 0.0,
+            compressor_release_coef: 0.0,
+        };
+        dynamics.design();
+        dynamics
+    }
+
+    pub fn settings(&self) -> &DynamicsSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: DynamicsSettings) {
+        self.settings = settings;
+        self.design();
+    }
+
+    /// Gain reduction the compressor is currently applying, in dB.
+    pub fn reduction_db(&self) -> f32 {
+        self.reduction_db
+    }
+
+    fn design(&mut self) {
+        self.ceiling = i16::MAX as f32 * db_to_linear(self.settings.ceiling_db.min(0.0));
+        self.release_coef = self.time_coef(self.settings.release_ms);
+        if let Some(compressor) = self.settings.compressor {
+            self.attack_coef = self.time_coef(compressor.attack_ms);
+            self.compressor_release_coef = self.time_coef(compressor.release_ms);
+        } else {
+            self.reduction_db = 0.0;
+        }
+    }
+
+    fn time_coef(&self, ms: f32) -> f32 {
+        let frames = ms * 0.001 * self.sample_rate as f32;
+        if frames <= 0.0 {
+            0.0
+        } else {
+            libm::expf(-1.0 / frames)
+        }
+    }
+
+    /// Compressor gain for a frame peaking at `peak`, including makeup.
+    fn compressor_gain(&mut self, peak: f32) -> f32 {
+        let Some(compressor) = self.settings.compressor else {
+            return 1.0;
+        };
+        let level_db = 20.0 * libm::log10f(peak.max(1.0) / i16::MAX as f32);
+        let over_db = level_db - compressor.threshold_db;
+        let target_db = if over_db > 0.0 {
+            over_db * (1.0 - 1.0 / compressor.ratio.max(1.0))
+        } else {
+            0.0
+        };
+        let coef = if target_db > self.reduction_db {
+            self.attack_coef
+        } else {
+            self.compressor_release_coef
+        };
+        self.reduction_db = target_db + coef * (self.reduction_db - target_db);
+        db_to_linear(compressor.makeup_db - self.reduction_db)
+    }
+
+    /// Limiter gain for the frame leaving the delay line, given the peak of
+    /// the frame entering it.
+    fn limiter_gain(&mut self, peak: f32) -> f32 {
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        self.envelope = if required < self.envelope {
+            required
+        } else {
+            required + self.release_coef * (self.envelope - required)
+        };
+
+        while self
+            .hold
+            .back()
+            .is_some_and(|&(_, gain)| gain >= self.envelope)
+        {
+            self.hold.pop_back();
+        }
+        self.hold.push_back((self.frame_count, self.envelope));
+        while self
+            .hold
+            .front()
+            .is_some_and(|&(frame, _)| frame + self.lookahead as u64 <= self.frame_count)
+        {
+            self.hold.pop_front();
+        }
+        let held = self.hold.front().map_or(1.0, |&(_, gain)| gain);
+
+        self.window_sum += held - self.window[self.position];
+        self.window[self.position] = held;
+        if self.position == 0 {
+            // Keep the running sum from drifting.
+            self.window_sum = self.window.iter().sum();
+        }
+        self.window_sum / self.lookahead as f32
+    }
+}
+
+impl Processor for Dynamics {
+    fn configure(&mut self, format: StreamFormat) {
+        self.sample_rate = format.sample_rate;
+        self.channels = format.channels();
+        self.lookahead =
+            (libm::roundf(LOOKAHEAD_MS * 0.001 * format.sample_rate as f32) as usize).max(1);
+        self.design();
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.delay.clear();
+        self.delay.resize(self.lookahead * self.channels, 0.0);
+        self.window.clear();
+        self.window.resize(self.lookahead, 1.0);
+        self.window_sum = self.lookahead as f32;
+        self.position = 0;
+        self.hold.clear();
+        self.frame_count = 0;
+        self.envelope = 1.0;
+        self.reduction_db = 0.0;
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        let channels = self.channels;
+        for frame in block.frames_iter_mut() {
+            let peak = frame
+                .iter()
+                .fold(0.0_f32, |peak, &sample| peak.max((sample as f32).abs()));
+            let compressor_gain = self.compressor_gain(peak);
+            let gain = self.limiter_gain(peak * compressor_gain);
+
+            let slot = self.position * channels;
+            // The oldest frame in the ring went in `lookahead - 1` frames ago.
+            let oldest = (self.position + 1) % self.lookahead * channels;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                self.delay[slot + channel] = *sample as f32 * compressor_gain;
+                let delayed = self.delay[oldest + channel];
+                *sample =
+                    libm::roundf(delayed * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+
+            self.position = (self.position + 1) % self.lookahead;
+            self.frame_count += 1;
+        }
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.lookahead - 1
+    }
+}
@@ -0,0 +1,598 @@
+//! Processing chain between the decoder and the I2S sink.
+//!
+//! Every stage implements [`Processor`] and works in place on a [`Block`] of
+//! interleaved `i16` PCM. A [`DspChain`] runs any number of them in an order
+//! that can change at run time, and is itself a [`Processor`]. Stages only
+//! depend on `core` and `alloc`, so they can be driven on the host as well as
+//! from `player_task`.
+
+pub mod channel_map;
+pub mod crossfeed;
+pub mod dynamics;
+pub mod eq;
+pub mod resampler;
+pub mod time_stretch;
+
+use alloc::boxed::Box;
+use core::any::Any;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut, Index, IndexMut};
+
+use heapless::Vec;
+
+use crate::audio::pcm::{AudioBuffer, ChannelLayout};
+
+pub use crate::audio::pcm::StreamFormat;
+
+/// The `i16` PCM every stage works on.
+pub type Block<'a> = AudioBuffer<'a, i16>;
+
+pub trait Processor {
+    /// Prepares the stage for a new stream, dropping any history.
+    fn configure(&mut self, format: StreamFormat);
+
+    /// Drops any history, called on seeks and track changes.
+    fn reset(&mut self);
+
+    fn process(&mut self, block: &mut Block<'_>);
+
+    /// Delay the stage adds to the signal, in frames at its output rate.
+    fn latency_frames(&self) -> usize {
+        0
+    }
+
+    /// Format of the blocks coming out of the stage for a given input format.
+    fn output_format(&self, input: StreamFormat) -> StreamFormat {
+        input
+    }
+
+    /// Input frames the stage consumes to produce at most `output_frames`.
+    fn input_frames_for(&self, output_frames: usize) -> usize {
+        output_frames
+    }
+
+    /// Samples the stage had to saturate since the last call.
+    fn take_clipped_samples(&mut self) -> u32 {
+        0
+    }
+}
+
+/// Wraps a [`Processor`] with a bypass switch. Bypassed stages are not run and
+/// do not count towards the chain latency.
+pub struct Stage<P> {
+    processor: P,
+    bypassed: bool,
+}
+
+impl<P: Processor> Stage<P> {
+    pub fn new(processor: P) -> Self {
+        Self {
+            processor,
+            bypassed: false,
+        }
+    }
+
+    pub fn is_bypassed(&self) -> bool {
+        self.bypassed
+    }
+
+    /// Bypassing resets the stage so it starts clean when it is re-enabled.
+    pub fn set_bypass(&mut self, bypassed: bool) {
+        if bypassed && !self.bypassed {
+            self.processor.reset();
+        }
+        self.bypassed = bypassed;
+    }
+}
+
+impl<P> Deref for Stage<P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.processor
+    }
+}
+
+impl<P> DerefMut for Stage<P> {
+    fn deref_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+}
+
+impl<P: Processor> Processor for Stage<P> {
+    fn configure(&mut self, format: StreamFormat) {
+        self.processor.configure(format);
+    }
+
+    fn reset(&mut self) {
+        self.processor.reset();
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        if !self.bypassed {
+            self.processor.process(block);
+        }
+    }
+
+    fn latency_frames(&self) -> usize {
+        if self.bypassed {
+            0
+        } else {
+            self.processor.latency_frames()
+        }
+    }
+
+    fn output_format(&self, input: StreamFormat) -> StreamFormat {
+        if self.bypassed {
+            input
+        } else {
+            self.processor.output_format(input)
+        }
+    }
+
+    fn input_frames_for(&self, output_frames: usize) -> usize {
+        if self.bypassed {
+            output_frames
+        } else {
+            self.processor.input_frames_for(output_frames)
+        }
+    }
+    fn take_clipped_samples(&mut self) -> u32 {
+        self.processor.take_clipped_samples()
+    }
+}
+
+/// Most stages a [`DspChain`] holds.
+pub const MAX_STAGES: usize = 12;
+
+/// Typed handle to a stage of a [`DspChain`], handed out when the stage is
+/// added and used to reach it afterwards.
+pub struct StageId<P> {
+    id: u16,
+    processor: PhantomData<fn() -> P>,
+}
+
+impl<P> Clone for StageId<P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P> Copy for StageId<P> {}
+
+/// A [`Stage`] the chain can hand back as its concrete type.
+trait ChainStage: Processor {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<P: Processor + 'static> ChainStage for Stage<P> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// An ordered list of stages, run front to back. Stages are added, moved and
+/// removed at run time and reached through the [`StageId`] they were added
+/// with, `chain[id]` panics if the stage is no longer in the chain.
+pub struct DspChain {
+    stages: Vec<(u16, Box<dyn ChainStage>), MAX_STAGES>,
+    next_id: u16,
+    /// Format of the stream the chain was last configured for.
+    format: StreamFormat,
+}
+
+impl Default for DspChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DspChain {
+    pub const fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            next_id: 0,
+            format: StreamFormat {
+                sample_rate: 48_000,
+                layout: ChannelLayout::Stereo,
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Adds a stage at the end. Panics if the chain already holds
+    /// [`MAX_STAGES`].
+    pub fn push<P: Processor + 'static>(&mut self, processor: P) -> StageId<P> {
+        self.insert(self.len(), processor)
+    }
+
+    /// Adds a stage so it runs `index`th. Panics if the chain already holds
+    /// [`MAX_STAGES`] or `index` is past the end.
+    pub fn insert<P: Processor + 'static>(&mut self, index: usize, processor: P) -> StageId<P> {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self
+            .stages
+            .insert(index, (id, Box::new(Stage::new(processor))))
+            .is_err()
+        {
+            panic!("DspChain holds at most {} stages", MAX_STAGES);
+        }
+        StageId {
+            id,
+            processor: PhantomData,
+        }
+    }
+
+    /// Where in the chain the stage runs, `None` once it has been removed.
+    pub fn position<P>(&self, id: StageId<P>) -> Option<usize> {
+        self.stages
+            .iter()
+            .position(|(stage_id, _)| *stage_id == id.id)
+    }
+
+    /// Moves the stage so it runs `index`th. Panics if it is not in the chain
+    /// or `index` is past the end.
+    pub fn move_to<P>(&mut self, id: StageId<P>, index: usize) {
+        let from = self.position(id).expect("stage not in the chain");
+        let stage = self.stages.remove(from);
+        if self.stages.insert(index, stage).is_err() {
+            unreachable!();
+        }
+    }
+
+    /// Takes the stage out of the chain and hands its processor back.
+    pub fn remove<P: Processor + 'static>(&mut self, id: StageId<P>) -> Option<P> {
+        let index = self.position(id)?;
+        let (_, stage) = self.stages.remove(index);
+        stage
+            .into_any()
+            .downcast::<Stage<P>>()
+            .ok()
+            .map(|stage| stage.processor)
+    }
+
+    pub fn get<P: Processor + 'static>(&self, id: StageId<P>) -> Option<&Stage<P>> {
+        let index = self.position(id)?;
+        self.stages[index].1.as_any().downcast_ref()
+    }
+
+    pub fn get_mut<P: Processor + 'static>(&mut self, id: StageId<P>) -> Option<&mut Stage<P>> {
+        let index = self.position(id)?;
+        self.stages[index].1.as_any_mut().downcast_mut()
+    }
+}
+
+impl<P: Processor + 'static> Index<StageId<P>> for DspChain {
+    type Output = Stage<P>;
+
+    fn index(&self, id: StageId<P>) -> &Stage<P> {
+        self.get(id).expect("stage not in the chain")
+    }
+}
+
+impl<P: Processor + 'static> IndexMut<StageId<P>> for DspChain {
+    fn index_mut(&mut self, id: StageId<P>) -> &mut Stage<P> {
+        self.get_mut(id).expect("stage not in the chain")
+    }
+}
+
+impl Processor for DspChain {
+    fn configure(&mut self, format: StreamFormat) {
+        self.format = format;
+        let mut format = format;
+        for (_, stage) in &mut self.stages {
+            stage.configure(format);
+            format = stage.output_format(format);
+        }
+    }
+
+    fn reset(&mut self) {
+        for (_, stage) in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        for (_, stage) in &mut self.stages {
+            stage.process(block);
+        }
+    }
+
+    /// Sum of the stage latencies, each counted in frames at its own output
+    /// rate and converted to the rate coming out of the chain.
+    fn latency_frames(&self) -> usize {
+        let output_rate = self.output_format(self.format).sample_rate.max(1) as u64;
+        let mut format = self.format;
+        let mut frames = 0;
+        for (_, stage) in &self.stages {
+            format = stage.output_format(format);
+            frames +=
+                stage.latency_frames() as u64 * output_rate / format.sample_rate.max(1) as u64;
+        }
+        frames as usize
+    }
+
+    fn output_format(&self, input: StreamFormat) -> StreamFormat {
+        self.stages
+            .iter()
+            .fold(input, |format, (_, stage)| stage.output_format(format))
+    }
+
+    fn input_frames_for(&self, output_frames: usize) -> usize {
+        self.stages
+            .iter()
+            .rev()
+            .fold(output_frames, |frames, (_, stage)| {
+                stage.input_frames_for(frames)
+            })
+    }
+    fn take_clipped_samples(&mut self) -> u32 {
+        self.stages
+            .iter_mut()
+            .map(|(_, stage)| stage.take_clipped_samples())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::replaygain::ReplayGain;
+    use crate::audio::volume::Volume;
+    use channel_map::ChannelMap;
+    use crossfeed::Crossfeed;
+    use dynamics::Dynamics;
+    use eq::Equalizer;
+    use resampler::Resampler;
+    use time_stretch::TimeStretch;
+
+    /// Doubles every sample and halves the rate, counting what it is asked to do.
+    #[derive(Default)]
+    struct Probe {
+        processed: usize,
+        resets: usize,
+    }
+
+    impl Processor for Probe {
+        fn configure(&mut self, _format: StreamFormat) {}
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+
+        fn process(&mut self, block: &mut Block<'_>) {
+            self.processed += 1;
+            for sample in block.samples_mut() {
+                *sample *= 2;
+            }
+        }
+
+        fn latency_frames(&self) -> usize {
+            10
+        }
+
+        fn output_format(&self, input: StreamFormat) -> StreamFormat {
+            StreamFormat {
+                sample_rate: input.sample_rate / 2,
+                ..input
+            }
+        }
+
+        fn input_frames_for(&self, output_frames: usize) -> usize {
+            output_frames * 2
+        }
+    }
+
+    const FORMAT: StreamFormat = StreamFormat {
+        sample_rate: 48_000,
+        layout: ChannelLayout::Stereo,
+    };
+
+    #[test]
+    fn a_stage_runs_its_processor_until_bypassed() {
+        let mut stage = Stage::new(Probe::default());
+        let mut samples = [1i16, 2, 3, 4];
+        stage.process(&mut Block::new(&mut samples, 2, FORMAT));
+        assert_eq!(samples, [2, 4, 6, 8]);
+        assert_eq!(stage.latency_frames(), 10);
+        assert_eq!(stage.output_format(FORMAT).sample_rate, 24_000);
+        assert_eq!(stage.input_frames_for(5), 10);
+
+        stage.set_bypass(true);
+        stage.process(&mut Block::new(&mut samples, 2, FORMAT));
+        assert_eq!(samples, [2, 4, 6, 8]);
+        assert_eq!(stage.processed, 1);
+        assert_eq!(stage.latency_frames(), 0);
+        assert_eq!(stage.output_format(FORMAT), FORMAT);
+        assert_eq!(stage.input_frames_for(5), 5);
+    }
+
+    #[test]
+    fn bypassing_resets_the_stage_once() {
+        let mut stage = Stage::new(Probe::default());
+        stage.set_bypass(true);
+        stage.set_bypass(true);
+        assert_eq!(stage.resets, 1);
+        stage.set_bypass(false);
+        assert!(!stage.is_bypassed());
+        assert_eq!(stage.resets, 1);
+    }
+
+    /// Appends its digit to every sample, so the output spells the order the
+    /// stages ran in.
+    struct Digit(i16);
+
+    impl Processor for Digit {
+        fn configure(&mut self, _format: StreamFormat) {}
+
+        fn reset(&mut self) {}
+
+        fn process(&mut self, block: &mut Block<'_>) {
+            for sample in block.samples_mut() {
+                *sample = *sample * 10 + self.0;
+            }
+        }
+    }
+
+    fn run(chain: &mut DspChain) -> i16 {
+        let mut samples = [0i16; 2];
+        chain.process(&mut Block::new(&mut samples, 1, FORMAT));
+        samples[0]
+    }
+
+    #[test]
+    fn stages_run_in_chain_order_and_can_be_rearranged() {
+        let mut chain = DspChain::new();
+        let one = chain.push(Digit(1));
+        let two = chain.push(Digit(2));
+        let three = chain.insert(0, Digit(3));
+        assert_eq!(run(&mut chain), 312);
+
+        chain.move_to(three, 2);
+        assert_eq!(chain.position(three), Some(2));
+        assert_eq!(run(&mut chain), 123);
+
+        chain[two].set_bypass(true);
+        assert_eq!(run(&mut chain), 13);
+
+        assert_eq!(chain.remove(one).map(|digit| digit.0), Some(1));
+        assert_eq!(chain.position(one), None);
+        assert!(chain.get(one).is_none());
+        assert_eq!(chain.len(), 2);
+        assert_eq!(run(&mut chain), 3);
+    }
+
+    #[test]
+    fn the_chain_folds_its_stages() {
+        let mut chain = DspChain::new();
+        let first = chain.push(Probe::default());
+        chain.push(Probe::default());
+        chain.configure(FORMAT);
+        // 10 frames at 24 kHz out of the first are 5 at the 12 kHz out of the last.
+        assert_eq!(chain.latency_frames(), 15);
+        assert_eq!(chain.output_format(FORMAT).sample_rate, 12_000);
+        assert_eq!(chain.input_frames_for(5), 20);
+
+        chain.reset();
+        assert_eq!(chain[first].resets, 1);
+        let mut samples = [1i16, 2];
+        chain.process(&mut Block::new(&mut samples, 1, FORMAT));
+        assert_eq!(samples, [4, 8]);
+        assert_eq!(chain[first].processed, 1);
+    }
+
+    /// Resampler, volume and a map to stereo, the way `player_task` ends its chain.
+    fn stereo_chain() -> DspChain {
+        let mut chain = DspChain::new();
+        chain.push(Resampler::new(48_000));
+        chain.push(Volume::new(0.0));
+        chain.push(ChannelMap::new(ChannelLayout::Stereo));
+        chain
+    }
+
+    #[test]
+    fn mono_comes_out_on_both_sides() {
+        let mut chain = stereo_chain();
+        let mono = StreamFormat {
+            layout: ChannelLayout::Mono,
+            ..FORMAT
+        };
+        chain.configure(mono);
+        assert_eq!(chain.output_format(mono), FORMAT);
+
+        let mut samples = [0i16; 64];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample = i as i16 * 100;
+        }
+        let mut block = Block::new(&mut samples, 64, mono);
+        block.limit_frames(64 / FORMAT.channels());
+        assert_eq!(block.frames(), 32);
+        chain.process(&mut block);
+        assert_eq!(block.format(), FORMAT);
+        assert_eq!(block.frames(), 32);
+        for (i, frame) in block.frames_iter().enumerate() {
+            assert_eq!(frame, [i as i16 * 100; 2]);
+        }
+    }
+
+    #[test]
+    fn surround_is_downmixed_without_the_lfe() {
+        let mut chain = stereo_chain();
+        let surround = StreamFormat {
+            layout: ChannelLayout::Multi(6),
+            ..FORMAT
+        };
+        chain.configure(surround);
+        assert_eq!(chain.output_format(surround), FORMAT);
+
+        // Left, right, centre, LFE, surround left, surround right.
+        let frame = [10_000i16, -10_000, 5_000, 30_000, 4_000, -4_000];
+        let mut samples = [0i16; 6 * 10];
+        for chunk in samples.chunks_exact_mut(6) {
+            chunk.copy_from_slice(&frame);
+        }
+        let mut block = Block::new(&mut samples, 10, surround);
+        chain.process(&mut block);
+        assert_eq!(block.format(), FORMAT);
+        assert_eq!(block.frames(), 10);
+        let side = 1.0 + 2.0 * core::f32::consts::FRAC_1_SQRT_2;
+        let left = (10_000.0 + (5_000.0 + 4_000.0) * core::f32::consts::FRAC_1_SQRT_2) / side;
+        let right = (-10_000.0 + (5_000.0 - 4_000.0) * core::f32::consts::FRAC_1_SQRT_2) / side;
+        for frame in block.frames_iter() {
+            assert!((frame[0] as f32 - left).abs() <= 1.0, "{:?}", frame);
+            assert!((frame[1] as f32 - right).abs() <= 1.0, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn a_full_scale_downmix_does_not_clip() {
+        let mut chain = stereo_chain();
+        let surround = StreamFormat {
+            layout: ChannelLayout::Multi(8),
+            ..FORMAT
+        };
+        chain.configure(surround);
+        let mut samples = [i16::MAX; 8 * 4];
+        let mut block = Block::new(&mut samples, 4, surround);
+        chain.process(&mut block);
+        for &sample in block.samples() {
+            assert!((i16::MAX - 1..=i16::MAX).contains(&sample));
+        }
+    }
+
+    #[test]
+    fn the_chain_resamples_to_the_output_rate() {
+        let mut chain = DspChain::new();
+        chain.push(ReplayGain::new(Default::default()));
+        chain.push(Resampler::new(48_000));
+        chain.push(TimeStretch::new(1.0));
+        chain.push(Equalizer::new(eq::EqPreset::flat()));
+        chain.push(Crossfeed::new(Default::default()));
+        chain.push(Volume::new(0.0));
+        chain.push(Dynamics::new(Default::default()));
+        let input = StreamFormat {
+            sample_rate: 44_100,
+            ..FORMAT
+        };
+        assert_eq!(chain.output_format(input).sample_rate, 48_000);
+        assert_eq!(chain.output_format(FORMAT), FORMAT);
+    }
+}
@@ -0,0 +1,225 @@
+//! Polyphase sample-rate converter to a fixed output rate.
+//!
+//! The prototype low pass is a Kaiser windowed sinc with [`ZERO_CROSSINGS`]
+//! zero crossings per side, tabulated at [`PHASES`] points per input sample
+//! and linearly interpolated between phases, so any ratio between 8 kHz and
+//! 192 kHz is handled with the same code. When downsampling the cutoff is
+//! scaled by the ratio and the filter gets proportionally longer.
+
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::round_and_saturate;
+
+pub const MIN_INPUT_RATE: u32 = 8_000;
+pub const MAX_INPUT_RATE: u32 = 192_000;
+
+const ZERO_CROSSINGS: f32 = 8.0;
+const PHASES: usize = 64;
+/// Passband edge as a fraction of the lower of the two Nyquist rates.
+const ROLLOFF: f32 = 0.92;
+const KAISER_BETA: f32 = 8.6;
+const FRAC_BITS: u32 = 32;
+
+pub struct Resampler {
+    output_rate: u32,
+    input_rate: u32,
+    channels: usize,
+    /// Input position of the next output frame, Q32 relative to `history[0]`.
+    position: u64,
+    /// Q32 input frames advanced per output frame.
+    step: u64,
+    /// Half the filter length, in input frames.
+    half_taps: usize,
+    /// One side of the symmetric impulse response, `PHASES` points per input frame.
+    kernel: Vec<f32>,
+    /// Interleaved input frames not yet fully consumed.
+    history: Vec<i16>,
+    /// Filter taps for the output frame being computed, shared by all channels.
+    weights: Vec<f32>,
+    clipped: u32,
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Self {
+        Self {
+            output_rate,
+            input_rate: output_rate,
+            channels: 2,
+            position: 0,
+            step: 1 << FRAC_BITS,
+            half_taps: 0,
+            kernel: Vec::new(),
+            history: Vec::new(),
+            weights: Vec::new(),
+            clipped: 0,
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Takes effect on the next [`Processor::configure`].
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        self.output_rate = output_rate;
+    }
+
+    fn is_passthrough(&self) -> bool {
+        self.input_rate == self.output_rate
+    }
+
+    fn history_frames(&self) -> usize {
+        self.history.len() / self.channels
+    }
+
+    fn design(&mut self) {
+        self.kernel.clear();
+        self.history.clear();
+        if self.is_passthrough() {
+            self.half_taps = 0;
+            return;
+        }
+        let cutoff = ROLLOFF * (self.output_rate as f32 / self.input_rate as f32).min(1.0);
+        let half_width = ZERO_CROSSINGS / cutoff;
+        self.half_taps = libm::ceilf(half_width) as usize;
+        let points = self.half_taps * PHASES + 2;
+        let i0_beta = bessel_i0(KAISER_BETA);
+        self.weights.resize(2 * self.half_taps, 0.0);
+        self.kernel.reserve_exact(points);
+        for i in 0..points {
+            let t = i as f32 / PHASES as f32;
+            let value = if t >= half_width {
+                0.0
+            } else {
+                let ratio = t / half_width;
+                let window = bessel_i0(KAISER_BETA * libm::sqrtf(1.0 - ratio * ratio)) / i0_beta;
+                cutoff * sinc(cutoff * t) * window
+            };
+            self.kernel.push(value);
+        }
+    }
+
+    /// Frames of `history` needed before the output frame at `position` can be computed.
+    fn frames_needed_for(&self, position: u64) -> usize {
+        (position >> FRAC_BITS) as usize + self.half_taps + 1
+    }
+}
+
+impl Processor for Resampler {
+    fn configure(&mut self, format: StreamFormat) {
+        self.input_rate = format.sample_rate.clamp(MIN_INPUT_RATE, MAX_INPUT_RATE);
+        self.channels = format.channels();
+        self.step = ((self.input_rate as u64) << FRAC_BITS) / self.output_rate as u64;
+        self.design();
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        // Prime with silence so the first output frame lines up with the first input frame.
+        self.history.resize(self.half_taps * self.channels, 0);
+        self.position = (self.half_taps as u64) << FRAC_BITS;
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        let output_format = self.output_format(block.format());
+        if self.is_passthrough() {
+            return;
+        }
+        let channels = self.channels;
+        self.history.extend_from_slice(block.samples());
+
+        let capacity = block.capacity_frames();
+        let buffer = block.buffer_mut();
+        let mut produced = 0;
+        while produced < capacity && self.frames_needed_for(self.position) <= self.history_frames()
+        {
+            let center = (self.position >> FRAC_BITS) as usize;
+            let frac = (self.position & ((1 << FRAC_BITS) - 1)) as f32 / (1u64 << FRAC_BITS) as f32;
+            let first = center + 1 - self.half_taps;
+            for (i, weight) in self.weights.iter_mut().enumerate() {
+                *weight = kernel_at(&self.kernel, (first + i) as f32 - center as f32 - frac);
+            }
+            let frames = &self.history[first * channels..(center + self.half_taps + 1) * channels];
+            for channel in 0..channels {
+                let mut acc = 0.0;
+                for (frame, weight) in frames.chunks_exact(channels).zip(self.weights.iter()) {
+                    acc += frame[channel] as f32 * weight;
+                }
+                buffer[produced * channels + channel] = round_and_saturate(acc, &mut self.clipped);
+            }
+            produced += 1;
+            self.position += self.step;
+        }
+
+        // Drop the frames no later output can reach.
+        let center = (self.position >> FRAC_BITS) as usize;
+        let consumed = (center + 1)
+            .saturating_sub(self.half_taps)
+            .min(self.history_frames());
+        self.history.drain(..consumed * channels);
+        self.position -= (consumed as u64) << FRAC_BITS;
+
+        block.set_contents(produced, output_format);
+    }
+
+    fn latency_frames(&self) -> usize {
+        (self.half_taps as u64 * self.output_rate as u64 / self.input_rate as u64) as usize
+    }
+
+    fn output_format(&self, input: StreamFormat) -> StreamFormat {
+        StreamFormat {
+            sample_rate: self.output_rate,
+            ..input
+        }
+    }
+
+    fn input_frames_for(&self, output_frames: usize) -> usize {
+        if self.is_passthrough() || output_frames == 0 {
+            return output_frames;
+        }
+        let last_position = self.position + (output_frames as u64 - 1) * self.step;
+        // Always ask for something, a zero sized read looks like the end of the stream.
+        self.frames_needed_for(last_position)
+            .saturating_sub(self.history_frames())
+            .max(1)
+    }
+    fn take_clipped_samples(&mut self) -> u32 {
+        core::mem::take(&mut self.clipped)
+    }
+}
+
+#[inline(always)]
+fn kernel_at(kernel: &[f32], t: f32) -> f32 {
+    let index = libm::fabsf(t) * PHASES as f32;
+    let i = index as usize;
+    if i + 1 >= kernel.len() {
+        return 0.0;
+    }
+    let frac = index - i as f32;
+    kernel[i] + (kernel[i + 1] - kernel[i]) * frac
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        libm::sinf(PI * x) / (PI * x)
+    }
+}
+
+/// Zeroth order modified Bessel function of the first kind, for the Kaiser window.
+fn bessel_i0(x: f32) -> f32 {
+    let half_x = x / 2.0;
+    let (mut sum, mut term) = (1.0, 1.0);
+    for k in 1..32 {
+        term *= half_x / k as f32;
+        sum += term * term;
+        if term * term < sum * 1e-9 {
+            break;
+        }
+    }
+    sum
+}
@@ -0,0 +1,382 @@
+//! WSOLA time stretching, changes the playback speed without changing the pitch.
+//!
+//! Every [`HOP_MS`] of output a [`WINDOW_MS`] long Hann windowed segment is
+//! overlap-added at 50%. The segments are taken `speed` hops apart from the
+//! input, each one nudged by up to [`SEEK_MS`] to wherever it lines up best
+//! with the natural continuation of the previous segment, which is what keeps
+//! voices from warbling. The search runs on a decimated mono mix and is then
+//! refined around the best match, to keep it cheap enough for the S3.
+
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+pub const WINDOW_MS: u32 = 20;
+pub const HOP_MS: u32 = WINDOW_MS / 2;
+pub const SEEK_MS: u32 = 8;
+
+/// Frame stride of the coarse search.
+const COARSE_STEP: usize = 4;
+const FRAC_BITS: u32 = 32;
+
+pub struct TimeStretch {
+    speed: f32,
+    sample_rate: u32,
+    channels: usize,
+    window_frames: usize,
+    hop_frames: usize,
+    seek_frames: usize,
+    /// Q32 input frames advanced per output hop.
+    analysis_hop: u64,
+    /// Hann window over `window_frames`.
+    window: Vec<f32>,
+    /// Interleaved input, `input[0]` is absolute input frame `input_offset`.
+    input: Vec<i16>,
+    input_offset: u64,
+    /// Q32 absolute input frame the next segment is nominally taken from.
+    nominal: u64,
+    /// Absolute input frame the previous segment was taken from.
+    previous: Option<u64>,
+    /// Second half of the previous windowed segment, interleaved.
+    overlap: Vec<f32>,
+    /// Mono template the next segment is matched against.
+    template: Vec<f32>,
+    /// Finished output frames that did not fit in the block.
+    pending: Vec<i16>,
+}
+
+impl TimeStretch {
+    pub fn new(speed: f32) -> Self {
+        let mut time_stretch = Self {
+            speed: 1.0,
+            sample_rate: 48_000,
+            channels: 2,
+            window_frames: 0,
+            hop_frames: 0,
+            seek_frames: 0,
+            analysis_hop: 0,
+            window: Vec::new(),
+            input: Vec::new(),
+            input_offset: 0,
+            nominal: 0,
+            previous: None,
+            overlap: Vec::new(),
+            template: Vec::new(),
+            pending: Vec::new(),
+        };
+        time_stretch.design();
+        time_stretch.set_speed(speed);
+        time_stretch
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Clamped to [`MIN_SPEED`]`..=`[`MAX_SPEED`], NaN is ignored. Resets the
+    /// stretch state, so expect a small jump in the audio.
+    pub fn set_speed(&mut self, speed: f32) {
+        let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        if !speed.is_nan() && speed != self.speed {
+            self.speed = speed;
+            self.design();
+            self.reset();
+        }
+    }
+
+    fn is_passthrough(&self) -> bool {
+        self.speed == 1.0
+    }
+
+    fn design(&mut self) {
+        self.hop_frames = (self.sample_rate * HOP_MS / 1000) as usize;
+        self.window_frames = 2 * self.hop_frames;
+        self.seek_frames = (self.sample_rate * SEEK_MS / 1000) as usize;
+        self.analysis_hop =
+            (self.hop_frames as f32 * self.speed * (1u64 << FRAC_BITS) as f32) as u64;
+        // Periodic Hann, two of them overlapping by half sum to exactly one.
+        self.window = (0..self.window_frames)
+            .map(|i| 0.5 - 0.5 * libm::cosf(2.0 * PI * i as f32 / self.window_frames as f32))
+            .collect();
+    }
+
+    fn input_end(&self) -> u64 {
+        self.input_offset + (self.input.len() / self.channels) as u64
+    }
+
+    /// Mono mix of absolute input frame `frame`.
+    fn mono(&self, frame: u64) -> f32 {
+        let start = (frame - self.input_offset) as usize * self.channels;
+        self.input[start..start + self.channels]
+            .iter()
+            .map(|&s| s as f32)
+            .sum()
+    }
+
+    /// Normalized correlation of the segment at `candidate` with the template,
+    /// using every `stride`th frame.
+    fn similarity(&self, candidate: u64, stride: usize) -> f32 {
+        let (mut correlation, mut energy) = (0.0, 1.0);
+        for i in (0..self.template.len()).step_by(stride) {
+            let x = self.mono(candidate + i as u64);
+            correlation += x * self.template[i];
+            energy += x * x;
+        }
+        correlation / libm::sqrtf(energy)
+    }
+
+    /// Picks where to take the next segment from, near `nominal`.
+    fn best_position(&mut self, nominal: u64) -> u64 {
+        let Some(previous) = self.previous else {
+            return nominal;
+        };
+        let natural = previous + self.hop_frames as u64;
+        self.template.clear();
+        for i in 0..self.hop_frames as u64 {
+            let x = self.mono(natural + i);
+            self.template.push(x);
+        }
+        let first = nominal
+            .saturating_sub(self.seek_frames as u64)
+            .max(self.input_offset);
+        let last = nominal + self.seek_frames as u64;
+
+        let mut best = (nominal, f32::MIN);
+        for candidate in (first..=last).step_by(COARSE_STEP) {
+            let score = self.similarity(candidate, COARSE_STEP);
+            if score > best.1 {
+                best = (candidate, score);
+            }
+        }
+        let coarse = best.0;
+        best = (coarse, self.similarity(coarse, 1));
+        let refine_first = coarse.saturating_sub(COARSE_STEP as u64 - 1).max(first);
+        let refine_last = (coarse + COARSE_STEP as u64 - 1).min(last);
+        for candidate in refine_first..=refine_last {
+            let score = self.similarity(candidate, 1);
+            if score > best.1 {
+                best = (candidate, score);
+            }
+        }
+        best.0
+    }
+
+    /// Overlap-adds one more segment, `None` if there is not enough input yet.
+    fn step(&mut self) -> Option<()> {
+        let nominal = self.nominal >> FRAC_BITS;
+        if nominal + (self.seek_frames + self.window_frames) as u64 > self.input_end() {
+            return None;
+        }
+        let chosen = self.best_position(nominal);
+        let channels = self.channels;
+        let start = (chosen - self.input_offset) as usize * channels;
+        let segment = &self.input[start..start + self.window_frames * channels];
+
+        let (head, tail) = segment.split_at(self.hop_frames * channels);
+        let (head_window, tail_window) = self.window.split_at(self.hop_frames);
+        for ((frame, &w), overlap) in head
+            .chunks_exact(channels)
+            .zip(head_window)
+            .zip(self.overlap.chunks_exact(channels))
+        {
+            for (&sample, &tail) in frame.iter().zip(overlap) {
+                let y = sample as f32 * w + tail;
+                self.pending
+                    .push(libm::roundf(y).clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            }
+        }
+        for ((frame, &w), overlap) in tail
+            .chunks_exact(channels)
+            .zip(tail_window)
+            .zip(self.overlap.chunks_exact_mut(channels))
+        {
+            for (&sample, tail) in frame.iter().zip(overlap) {
+                *tail = sample as f32 * w;
+            }
+        }
+
+        self.previous = Some(chosen);
+        self.nominal += self.analysis_hop;
+        Some(())
+    }
+
+    /// Drops input no later segment or template can reach.
+    fn discard_input(&mut self) {
+        let next = (self.nominal >> FRAC_BITS).saturating_sub(self.seek_frames as u64);
+        let keep_from = match self.previous {
+            Some(previous) => next.min(previous + self.hop_frames as u64),
+            None => next,
+        }
+        .clamp(self.input_offset, self.input_end());
+        let frames = (keep_from - self.input_offset) as usize;
+        self.input.drain(..frames * self.channels);
+        self.input_offset = keep_from;
+    }
+
+    fn pending_frames(&self) -> usize {
+        self.pending.len() / self.channels
+    }
+}
+
+impl Processor for TimeStretch {
+    fn configure(&mut self, format: StreamFormat) {
+        self.sample_rate = format.sample_rate;
+        self.channels = format.channels();
+        self.design();
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.input_offset = 0;
+        self.nominal = 0;
+        self.previous = None;
+        self.overlap.clear();
+        self.overlap.resize(self.hop_frames * self.channels, 0.0);
+        self.pending.clear();
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        if self.is_passthrough() {
+            return;
+        }
+        let format = block.format();
+        self.input.extend_from_slice(block.samples());
+
+        let capacity = block.capacity_frames();
+        while self.pending_frames() < capacity && self.step().is_some() {}
+        self.discard_input();
+
+        let frames = self.pending_frames().min(capacity);
+        let samples = frames * self.channels;
+        block.buffer_mut()[..samples].copy_from_slice(&self.pending[..samples]);
+        self.pending.drain(..samples);
+        block.set_contents(frames, format);
+    }
+
+    fn latency_frames(&self) -> usize {
+        if self.is_passthrough() {
+            0
+        } else {
+            self.hop_frames
+        }
+    }
+
+    fn input_frames_for(&self, output_frames: usize) -> usize {
+        if self.is_passthrough() || output_frames == 0 {
+            return output_frames;
+        }
+        let missing = output_frames.saturating_sub(self.pending_frames());
+        let steps = missing.div_ceil(self.hop_frames) as u64;
+        let last_nominal =
+            (self.nominal + steps.saturating_sub(1) * self.analysis_hop) >> FRAC_BITS;
+        let needed = last_nominal + (self.seek_frames + self.window_frames) as u64;
+        // Always ask for something, a zero sized read looks like the end of the stream.
+        (needed.saturating_sub(self.input_end()) as usize).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::pcm::ChannelLayout;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        sample_rate: 48_000,
+        layout: ChannelLayout::Stereo,
+    };
+    const MONO: StreamFormat = StreamFormat {
+        layout: ChannelLayout::Mono,
+        ..FORMAT
+    };
+
+    /// Two seconds of a mono sine at half scale.
+    fn tone(hz: f32) -> Vec<i16> {
+        (0..2 * MONO.sample_rate)
+            .map(|i| {
+                let phase = 2.0 * PI * hz * i as f32 / MONO.sample_rate as f32;
+                (16_384.0 * libm::sinf(phase)) as i16
+            })
+            .collect()
+    }
+
+    /// Runs `input` through at `speed` in blocks of 1024 frames, the way the
+    /// player hands them over.
+    fn stretch(input: &[i16], speed: f32) -> Vec<i16> {
+        let mut time_stretch = TimeStretch::new(speed);
+        time_stretch.configure(MONO);
+        let mut output = Vec::new();
+        let mut buffer = [0i16; 4_096];
+        for chunk in input.chunks(1_024) {
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            let mut block = Block::new(&mut buffer, chunk.len(), MONO);
+            time_stretch.process(&mut block);
+            output.extend_from_slice(block.samples());
+        }
+        output
+    }
+
+    #[test]
+    fn the_output_is_as_long_as_the_speed_says() {
+        let input = tone(1_000.0);
+        for speed in [0.5, 0.75, 1.5, 3.0] {
+            let expected = input.len() as f32 / speed;
+            let output = stretch(&input, speed);
+            // Short by what is still held back for the next segments.
+            let held_back = (WINDOW_MS + SEEK_MS) * MONO.sample_rate / 1000;
+            let missing = expected - output.len() as f32;
+            assert!(
+                (0.0..=held_back as f32 / speed).contains(&missing),
+                "{speed}x: {} frames out, {expected} expected",
+                output.len()
+            );
+        }
+    }
+
+    #[test]
+    fn a_tone_keeps_its_pitch() {
+        for speed in [0.75, 1.5] {
+            let output = stretch(&tone(1_000.0), speed);
+            // Counts the upward zero crossings past the first window.
+            let steady = &output[(2 * WINDOW_MS * MONO.sample_rate / 1000) as usize..];
+            let crossings = steady
+                .windows(2)
+                .filter(|pair| pair[0] < 0 && pair[1] >= 0)
+                .count();
+            let hz = crossings as f32 * MONO.sample_rate as f32 / steady.len() as f32;
+            assert!((hz - 1_000.0).abs() < 10.0, "{speed}x: {hz} Hz");
+        }
+    }
+
+    #[test]
+    fn a_new_stream_keeps_the_speed_and_drops_the_rest() {
+        let mut time_stretch = TimeStretch::new(1.0);
+        time_stretch.configure(FORMAT);
+        time_stretch.set_speed(1.5);
+        let mut samples = [1_000i16; 2 * 4_800];
+        time_stretch.process(&mut Block::new(&mut samples, 4_800, FORMAT));
+        assert!(time_stretch.input_end() > 0);
+
+        time_stretch.configure(StreamFormat {
+            sample_rate: 44_100,
+            ..FORMAT
+        });
+        assert_eq!(time_stretch.speed(), 1.5);
+        assert_eq!(time_stretch.input_end(), 0);
+        assert_eq!(time_stretch.pending_frames(), 0);
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut time_stretch = TimeStretch::new(10.0);
+        assert_eq!(time_stretch.speed(), MAX_SPEED);
+        time_stretch.set_speed(0.0);
+        assert_eq!(time_stretch.speed(), MIN_SPEED);
+        time_stretch.set_speed(f32::NAN);
+        assert_eq!(time_stretch.speed(), MIN_SPEED);
+    }
+}
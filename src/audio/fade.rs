@@ -0,0 +1,129 @@
+//! Gain ramps that keep the output free of clicks.
+//!
+//! [`Fade`] moves its gain linearly towards the target one step per frame, so
+//! reversing a fade half way through carries on from wherever the gain is and
+//! never jumps. When the audio stops dead instead, [`Fade::tail`] ramps the
+//! last frame that went out down to silence.
+
+use crate::audio::gain::UNITY_Q16;
+use crate::audio::pcm::AudioBuffer;
+
+/// Channels [`Fade::tail`] remembers the last frame of.
+const TAIL_CHANNELS: usize = 2;
+
+/// How long a full fade takes in each direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FadeSettings {
+    pub fade_in_ms: u32,
+    pub fade_out_ms: u32,
+}
+
+impl Default for FadeSettings {
+    fn default() -> Self {
+        Self {
+            fade_in_ms: 15,
+            fade_out_ms: 30,
+        }
+    }
+}
+
+pub struct Fade {
+    sample_rate: u32,
+    fade_out_frames: usize,
+    gain_q16: i32,
+    target_q16: i32,
+    in_step_q16: i32,
+    out_step_q16: i32,
+    /// Last frame [`Fade::apply`] let through.
+    last: [i16; TAIL_CHANNELS],
+}
+
+impl Fade {
+    /// Starts fully faded in.
+    pub fn new(sample_rate: u32, settings: FadeSettings) -> Self {
+        let mut fade = Self {
+            sample_rate,
+            fade_out_frames: 1,
+            gain_q16: UNITY_Q16,
+            target_q16: UNITY_Q16,
+            in_step_q16: UNITY_Q16,
+            out_step_q16: UNITY_Q16,
+            last: [0; TAIL_CHANNELS],
+        };
+        fade.set_settings(settings);
+        fade
+    }
+
+    pub fn set_settings(&mut self, settings: FadeSettings) {
+        let frames = |ms: u32| (self.sample_rate * ms / 1000).max(1) as usize;
+        self.fade_out_frames = frames(settings.fade_out_ms);
+        self.in_step_q16 = (UNITY_Q16 / frames(settings.fade_in_ms) as i32).max(1);
+        self.out_step_q16 = (UNITY_Q16 / self.fade_out_frames as i32).max(1);
+    }
+
+    pub fn fade_in(&mut self) {
+        self.target_q16 = UNITY_Q16;
+    }
+
+    pub fn fade_out(&mut self) {
+        self.target_q16 = 0;
+    }
+
+    /// Jumps straight to silence.
+    pub fn mute(&mut self) {
+        self.gain_q16 = 0;
+        self.target_q16 = 0;
+        self.last = [0; TAIL_CHANNELS];
+    }
+
+    /// How many frames a full fade out takes.
+    pub fn fade_out_frames(&self) -> usize {
+        self.fade_out_frames
+    }
+
+    /// At full gain and staying there.
+    pub fn is_open(&self) -> bool {
+        self.gain_q16 == UNITY_Q16 && self.target_q16 == UNITY_Q16
+    }
+
+    /// Faded out all the way, nothing [`Fade::apply`] outputs is audible.
+    pub fn is_silent(&self) -> bool {
+        self.gain_q16 == 0 && self.target_q16 == 0
+    }
+
+    /// Applies the ramp to the buffer.
+    pub fn apply(&mut self, buffer: &mut AudioBuffer<'_, i16>) {
+        if self.gain_q16 != UNITY_Q16 || self.target_q16 != UNITY_Q16 {
+            for frame in buffer.frames_iter_mut() {
+                self.gain_q16 = if self.gain_q16 < self.target_q16 {
+                    (self.gain_q16 + self.in_step_q16).min(self.target_q16)
+                } else {
+                    (self.gain_q16 - self.out_step_q16).max(self.target_q16)
+                };
+                for sample in frame.iter_mut() {
+                    *sample = ((*sample as i32 * self.gain_q16) >> 16) as i16;
+                }
+            }
+        }
+        if let Some(frame) = buffer.frames_iter().last() {
+            for (last, &sample) in self.last.iter_mut().zip(frame) {
+                *last = sample;
+            }
+        }
+    }
+
+    /// Replaces the contents of `buffer` with a fade out of the last frame
+    /// that went out, for when the audio ends without warning, and mutes.
+    pub fn tail(&mut self, buffer: &mut AudioBuffer<'_, i16>) {
+        let frames = self.fade_out_frames.min(buffer.capacity_frames());
+        buffer.set_contents(frames, buffer.format());
+        for (i, frame) in buffer.frames_iter_mut().enumerate() {
+            let gain_q16 = ((frames - 1 - i) << 16) / frames;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let last = self.last[channel.min(TAIL_CHANNELS - 1)];
+                *sample = ((last as i64 * gain_q16 as i64) >> 16) as i16;
+            }
+        }
+        self.mute();
+    }
+}
@@ -0,0 +1,48 @@
+//! Fixed point gain helpers shared by the playback path.
+//!
+//! Gains are carried as Q16 (`1.0 == 1 << 16`) so they can be applied to
+//! `i16` PCM with a single multiply and shift.
+
+pub const UNITY_Q16: i32 = 1 << 16;
+
+pub fn db_to_linear(db: f32) -> f32 {
+    libm::powf(10.0, db / 20.0)
+}
+
+pub fn linear_to_db(linear: f32) -> f32 {
+    20.0 * libm::log10f(linear)
+}
+
+pub fn linear_to_q16(linear: f32) -> i32 {
+    (linear * UNITY_Q16 as f32).clamp(0.0, i32::MAX as f32) as i32
+}
+
+pub fn db_to_q16(db: f32) -> i32 {
+    linear_to_q16(db_to_linear(db))
+}
+
+/// Multiplies every sample by `gain_q16`, saturating at the `i16` limits.
+/// Returns how many samples had to be saturated.
+pub fn apply_gain_q16(samples: &mut [i16], gain_q16: i32) -> u32 {
+    let mut clips = 0;
+    if gain_q16 == UNITY_Q16 {
+        return clips;
+    }
+    for sample in samples.iter_mut() {
+        *sample = saturate((*sample as i64 * gain_q16 as i64) >> 16, &mut clips);
+    }
+    clips
+}
+
+/// Saturates `value` to the `i16` range, counting it in `clips` if it had to.
+pub fn saturate(value: i64, clips: &mut u32) -> i16 {
+    if value > i16::MAX as i64 || value < i16::MIN as i64 {
+        *clips += 1;
+    }
+    value.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+
+/// Rounds `value` to the nearest `i16` and saturates it like [`saturate`].
+pub fn round_and_saturate(value: f32, clips: &mut u32) -> i16 {
+    saturate(libm::roundf(value) as i64, clips)
+}
@@ -0,0 +1,146 @@
+//! Output buffer health counters, for sizing the PCM ring and the DMA buffer.
+//!
+//! `player_task` and `output_task` record into [`BUFFER_HEALTH`] from their own
+//! cores through plain atomics, anything else can take a [`HealthSnapshot`]
+//! at any time, and [`health_log_task`] logs one every [`HEALTH_LOG_PERIOD_S`].
+//! Water marks and underruns only count while the player is streaming, a
+//! paused player is expected to leave the buffers empty.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use defmt::info;
+use embassy_time::{Duration, Timer};
+
+use crate::audio::ring::PCM_RING;
+
+pub const HEALTH_LOG_PERIOD_S: u64 = 5;
+
+pub static BUFFER_HEALTH: BufferHealth = BufferHealth::new();
+
+pub struct BufferHealth {
+    streaming: AtomicBool,
+    underruns: AtomicU32,
+    /// Silence pushed by all the underruns together.
+    underrun_frames: AtomicU32,
+    ring_low: AtomicUsize,
+    ring_high: AtomicUsize,
+    dma_low: AtomicUsize,
+    decode_calls: AtomicU32,
+    /// Wraps after about 70 minutes of decoding, only the average uses it.
+    decode_us_total: AtomicU32,
+    decode_us_max: AtomicU32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HealthSnapshot {
+    pub underruns: u32,
+    pub underrun_frames: u32,
+    /// Samples in the PCM ring right now.
+    pub ring_fill: usize,
+    /// Fewest samples the PCM ring held while streaming.
+    pub ring_low: usize,
+    /// Most samples the PCM ring held while streaming.
+    pub ring_high: usize,
+    /// Fewest bytes queued in the DMA buffer while streaming.
+    pub dma_low: usize,
+    pub decode_calls: u32,
+    pub decode_avg_us: u32,
+    pub decode_max_us: u32,
+}
+
+impl Default for BufferHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferHealth {
+    pub const fn new() -> Self {
+        Self {
+            streaming: AtomicBool::new(false),
+            underruns: AtomicU32::new(0),
+            underrun_frames: AtomicU32::new(0),
+            ring_low: AtomicUsize::new(usize::MAX),
+            ring_high: AtomicUsize::new(0),
+            dma_low: AtomicUsize::new(usize::MAX),
+            decode_calls: AtomicU32::new(0),
+            decode_us_total: AtomicU32::new(0),
+            decode_us_max: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether the player is producing audio, set by `player_task`.
+    pub(crate) fn set_streaming(&self, streaming: bool) {
+        self.streaming.store(streaming, Ordering::Relaxed);
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming.load(Ordering::Relaxed)
+    }
+
+    /// `output_task` had to push `frames` of silence, the ring ran dry.
+    pub(crate) fn record_underrun(&self, frames: usize) {
+        if self.is_streaming() {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+            self.underrun_frames
+                .fetch_add(frames as u32, Ordering::Relaxed);
+        }
+    }
+
+    /// Samples in the ring and bytes queued in the DMA buffer, as `output_task`
+    /// finds them before every push.
+    pub(crate) fn record_fill(&self, ring_samples: usize, dma_bytes: usize) {
+        if self.is_streaming() {
+            self.ring_low.fetch_min(ring_samples, Ordering::Relaxed);
+            self.ring_high.fetch_max(ring_samples, Ordering::Relaxed);
+            self.dma_low.fetch_min(dma_bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_decode(&self, micros: u32) {
+        self.decode_calls.fetch_add(1, Ordering::Relaxed);
+        self.decode_us_total.fetch_add(micros, Ordering::Relaxed);
+        self.decode_us_max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        let decode_calls = self.decode_calls.load(Ordering::Relaxed);
+        let ring_low = self.ring_low.load(Ordering::Relaxed);
+        let dma_low = self.dma_low.load(Ordering::Relaxed);
+        HealthSnapshot {
+            underruns: self.underruns.load(Ordering::Relaxed),
+            underrun_frames: self.underrun_frames.load(Ordering::Relaxed),
+            ring_fill: PCM_RING.len(),
+            // Nothing recorded yet reads as zero rather than `usize::MAX`.
+            ring_low: if ring_low == usize::MAX { 0 } else { ring_low },
+            ring_high: self.ring_high.load(Ordering::Relaxed),
+            dma_low: if dma_low == usize::MAX { 0 } else { dma_low },
+            decode_calls,
+            decode_avg_us: self.decode_us_total.load(Ordering::Relaxed) / decode_calls.max(1),
+            decode_max_us: self.decode_us_max.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts every counter over, for measuring one buffer size at a time.
+    pub fn reset(&self) {
+        self.underruns.store(0, Ordering::Relaxed);
+        self.underrun_frames.store(0, Ordering::Relaxed);
+        self.ring_low.store(usize::MAX, Ordering::Relaxed);
+        self.ring_high.store(0, Ordering::Relaxed);
+        self.dma_low.store(usize::MAX, Ordering::Relaxed);
+        self.decode_calls.store(0, Ordering::Relaxed);
+        self.decode_us_total.store(0, Ordering::Relaxed);
+        self.decode_us_max.store(0, Ordering::Relaxed);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn health_log_task() {
+    loop {
+        Timer::after(Duration::from_secs(HEALTH_LOG_PERIOD_S)).await;
+        info!(
+            "Buffer health: {}",
+            defmt::Debug2Format(&BUFFER_HEALTH.snapshot())
+        );
+    }
+}
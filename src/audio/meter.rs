@@ -0,0 +1,223 @@
+//! Peak, RMS and clip metering of the output stream.
+//!
+//! A [`LevelMeter`] runs as the last stage of the DSP chain and publishes one
+//! [`Levels`] reading every [`METER_PERIOD_MS`] to the [`LEVELS`] watch. The
+//! limiter keeps the output itself below full scale, so clips are counted
+//! where they happen instead: every stage that saturates reports it through
+//! [`Processor::take_clipped_samples`] and `player_task` hands the total to the
+//! meter.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::linear_to_db;
+
+pub const METER_CHANNELS: usize = 2;
+pub const METER_PERIOD_MS: u32 = 50;
+/// Number of tasks that can hold a [`LEVELS`] receiver at the same time.
+pub const LEVELS_RECEIVERS: usize = 4;
+
+pub static LEVELS: Watch<CriticalSectionRawMutex, Levels, LEVELS_RECEIVERS> = Watch::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevels {
+    /// Highest absolute sample over the period, 1.0 is full scale.
+    pub peak: f32,
+    /// RMS over the period, 1.0 is full scale.
+    pub rms: f32,
+}
+
+impl ChannelLevels {
+    pub fn peak_db(&self) -> f32 {
+        linear_to_db(self.peak)
+    }
+
+    pub fn rms_db(&self) -> f32 {
+        linear_to_db(self.rms)
+    }
+}
+
+/// Mono streams show up on both channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub channels: [ChannelLevels; METER_CHANNELS],
+    /// Samples the DSP chain saturated since the stream started.
+    pub clips: u32,
+}
+
+pub struct LevelMeter {
+    period_frames: usize,
+    frames: usize,
+    peak: [u16; METER_CHANNELS],
+    sum_squares: [u64; METER_CHANNELS],
+    clips: u32,
+    levels: Levels,
+}
+
+impl LevelMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            period_frames: period_frames(sample_rate),
+            frames: 0,
+            peak: [0; METER_CHANNELS],
+            sum_squares: [0; METER_CHANNELS],
+            clips: 0,
+            levels: Levels::default(),
+        }
+    }
+
+    /// The last reading published.
+    pub fn levels(&self) -> Levels {
+        self.levels
+    }
+
+    /// Counts samples the stages ahead of the meter had to saturate.
+    pub fn record_clips(&mut self, clips: u32) {
+        self.clips = self.clips.saturating_add(clips);
+    }
+
+    fn publish(&mut self) {
+        let mut levels = Levels {
+            clips: self.clips,
+            ..Levels::default()
+        };
+        for (channel, reading) in levels.channels.iter_mut().enumerate() {
+            let mean_square = self.sum_squares[channel] as f32 / self.frames as f32;
+            *reading = ChannelLevels {
+                peak: self.peak[channel] as f32 / 32768.0,
+                rms: libm::sqrtf(mean_square) / 32768.0,
+            };
+        }
+        LEVELS.sender().send(levels);
+        self.levels = levels;
+        self.frames = 0;
+        self.peak = [0; METER_CHANNELS];
+        self.sum_squares = [0; METER_CHANNELS];
+    }
+}
+
+/// Measures the blocks going through it, publishes to [`LEVELS`] at the end of
+/// every period.
+impl Processor for LevelMeter {
+    /// Starts over for a new stream, clip count included.
+    fn configure(&mut self, format: StreamFormat) {
+        self.period_frames = period_frames(format.sample_rate);
+        self.clips = 0;
+        self.levels = Levels::default();
+        self.reset();
+    }
+
+    /// Drops the period measured so far.
+    fn reset(&mut self) {
+        self.frames = 0;
+        self.peak = [0; METER_CHANNELS];
+        self.sum_squares = [0; METER_CHANNELS];
+    }
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        let channels = block.channels();
+        for frame in block.frames_iter() {
+            for channel in 0..METER_CHANNELS {
+                let sample = frame[channel.min(channels - 1)];
+                self.peak[channel] = self.peak[channel].max(sample.unsigned_abs());
+                self.sum_squares[channel] += (sample as i32 * sample as i32) as u64;
+            }
+            self.frames += 1;
+            if self.frames == self.period_frames {
+                self.publish();
+            }
+        }
+    }
+}
+
+fn period_frames(sample_rate: u32) -> usize {
+    ((sample_rate * METER_PERIOD_MS / 1000) as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::dsp::DspChain;
+    use crate::audio::pcm::ChannelLayout;
+    use crate::audio::volume::{Volume, VolumeCommand};
+    use alloc::vec;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        sample_rate: 1000,
+        layout: ChannelLayout::Stereo,
+    };
+    /// 50 ms at 1 kHz.
+    const PERIOD: usize = 50;
+
+    fn block(samples: &mut [i16]) -> Block<'_> {
+        let frames = samples.len() / 2;
+        Block::new(samples, frames, FORMAT)
+    }
+
+    #[test]
+    fn publishes_peak_and_rms_once_a_period() {
+        let mut meter = LevelMeter::new(FORMAT.sample_rate);
+        // Left is a square wave at -6 dBFS, right a single spike.
+        let mut samples = [0i16; 2 * PERIOD];
+        for (i, frame) in samples.chunks_exact_mut(2).enumerate() {
+            frame[0] = if i % 2 == 0 { 16_384 } else { -16_384 };
+        }
+        samples[1] = -32_768;
+
+        meter.process(&mut block(&mut samples[..2 * (PERIOD - 1)]));
+        assert_eq!(meter.levels(), Levels::default());
+        meter.process(&mut block(&mut samples[2 * (PERIOD - 1)..]));
+        let [left, right] = meter.levels().channels;
+        assert_eq!(left.peak, 0.5);
+        assert_eq!(left.rms, 0.5);
+        assert!((left.peak_db() + 6.02).abs() < 0.01);
+        assert_eq!(right.peak, 1.0);
+        assert!((right.rms - libm::sqrtf(1.0 / PERIOD as f32)).abs() < 1e-4);
+
+        // The next period starts from nothing.
+        let mut silence = vec![0i16; 2 * PERIOD];
+        meter.process(&mut block(&mut silence));
+        assert_eq!(meter.levels().channels, [ChannelLevels::default(); 2]);
+    }
+
+    #[test]
+    fn mono_shows_up_on_both_channels() {
+        let mono = StreamFormat {
+            layout: ChannelLayout::Mono,
+            ..FORMAT
+        };
+        let mut meter = LevelMeter::new(FORMAT.sample_rate);
+        meter.configure(mono);
+        let mut samples = vec![8_192i16; PERIOD];
+        meter.process(&mut Block::new(&mut samples, PERIOD, mono));
+        let [left, right] = meter.levels().channels;
+        assert_eq!(left, right);
+        assert_eq!(left.peak, 0.25);
+    }
+
+    #[test]
+    fn counts_what_the_chain_saturated_until_the_next_stream() {
+        let mut chain = DspChain::new();
+        let volume = chain.push(Volume::new(0.0));
+        let meter = chain.push(LevelMeter::new(FORMAT.sample_rate));
+        chain.configure(FORMAT);
+        chain[volume].handle_command(VolumeCommand::SetSoftwareGain(12.0));
+
+        let mut samples = vec![0i16; 2 * PERIOD];
+        samples[..4].copy_from_slice(&[20_000, -20_000, 1_000, -1_000]);
+        for _ in 0..2 {
+            chain.process(&mut block(&mut samples.clone()));
+            let clips = chain.take_clipped_samples();
+            chain[meter].record_clips(clips);
+        }
+        chain.process(&mut block(&mut samples.clone()));
+        assert_eq!(chain[meter].levels().clips, 4);
+        // The last block's are still to be handed over.
+        assert_eq!(chain.take_clipped_samples(), 2);
+
+        chain.configure(FORMAT);
+        chain.process(&mut block(&mut [0i16; 2 * PERIOD]));
+        assert_eq!(chain[meter].levels().clips, 0);
+    }
+}
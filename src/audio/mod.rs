@@ -1,21 +1,41 @@
+pub mod ab_repeat;
 pub(crate) mod codec;
 pub(crate) mod dr_flac_bindings;
+pub mod dsp;
+pub mod fade;
+pub mod gain;
+pub mod health;
+pub mod loudness;
+pub mod meter;
+pub mod output_format;
+pub mod pcm;
 pub mod player;
+pub mod playlist;
+pub mod replaygain;
+pub mod resume;
+pub mod ring;
+pub mod scanner;
+pub mod spectrum;
+pub mod status;
+pub mod volume;
 
 use core::cmp::min;
-use core::slice::from_raw_parts;
 
 use defmt::info;
 use defmt_rtt as _;
+use embassy_futures::select::{Either3, select3};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
-use embassy_time::{Duration, block_for};
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant, Timer, block_for, with_timeout};
 use esp_backtrace as _;
 use esp_hal::gpio::{Level, Output, OutputConfig};
 use esp_hal::i2c::master::Config as I2CConfig;
-use esp_hal::i2s::master::{Channels, Config as I2SConfig, DataFormat, I2s, I2sTx, UnitConfig};
+use esp_hal::i2c::master::I2c;
+use esp_hal::i2s::master::{Config as I2SConfig, I2s, UnitConfig};
 use esp_hal::time::Rate;
-use esp_hal::{Blocking, dma, dma_circular_buffers, i2c};
+use esp_hal::{Blocking, dma_circular_buffers, i2c};
 
 // use mousefood::ratatui::Terminal;
 // use mousefood::*;
@@ -23,21 +43,96 @@ use esp_hal::{Blocking, dma, dma_circular_buffers, i2c};
 use tlv320dac3100::TLV320DAC3100;
 use tlv320dac3100::typedefs::*;
 
+use crate::audio::ab_repeat::{AbRepeat, AbRepeatCommand};
 use crate::audio::codec::codec::Decoder;
-use crate::{DACPeripherals, DACResources};
+use crate::audio::dsp::channel_map::ChannelMap;
+use crate::audio::dsp::crossfeed::{Crossfeed, CrossfeedStrength};
+use crate::audio::dsp::dynamics::{Dynamics, DynamicsSettings};
+use crate::audio::dsp::eq::{EqPreset, Equalizer};
+use crate::audio::dsp::resampler::Resampler;
+use crate::audio::dsp::time_stretch::TimeStretch;
+use crate::audio::dsp::{Block, DspChain, Processor, StageId};
+use crate::audio::fade::{Fade, FadeSettings};
+use crate::audio::health::BUFFER_HEALTH;
+use crate::audio::meter::LevelMeter;
+use crate::audio::output_format::{OutputFormat, Stereo16};
+use crate::audio::pcm::{AudioBuffer, StreamFormat};
+pub use crate::audio::player::{
+    FileInfo, PLAYER_COMMANDS, PLAYER_QUEUE, QUEUE_RECEIVERS, QueueReceiver,
+};
+use crate::audio::player::{Player, PlayerAction, TrackRef};
+use crate::audio::replaygain::{ReplayGain, ReplayGainSettings};
+use crate::audio::resume::ResumePoint;
+use crate::audio::ring::{PCM_RING, SLOT_SAMPLES};
+use crate::audio::spectrum::{SpectrumAnalyzer, SpectrumConfig};
+use crate::audio::status::{PlaybackState, PlaybackStatus};
+use crate::audio::volume::{
+    DEFAULT_VOLUME_DB, HardwareVolume, SOFT_STEP_INTERVAL_MS, Volume, VolumeCommand,
+};
+use crate::{DACPeripherals, DACResources, I2SResources};
 
-#[derive(Clone, Copy, Debug)]
-pub struct FileInfo {
-    pub file_name: &'static str,
-    pub file_bytes: &'static [u8],
+/// Which amplifiers of the DAC are driven.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputRoute {
+    Headphones,
+    Speaker,
+    /// What `init` sets up.
+    #[default]
+    Both,
 }
-#[derive(Clone, Copy, Debug)]
-pub enum PlayPauseState {
-    Play,
-    Pause,
+/// Compressor/limiter settings for each amplifier.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DynamicsProfiles {
+    pub headphones: DynamicsSettings,
+    pub speaker: DynamicsSettings,
 }
-pub static PLAY_PAUSE_STATE: Signal<CriticalSectionRawMutex, PlayPauseState> = Signal::new();
-pub static AUDIO_DECODER: Signal<CriticalSectionRawMutex, FileInfo> = Signal::new();
+
+impl Default for DynamicsProfiles {
+    fn default() -> Self {
+        Self {
+            headphones: DynamicsSettings::headphones(),
+            speaker: DynamicsSettings::speaker(),
+        }
+    }
+}
+
+impl DynamicsProfiles {
+    /// The speaker is the weaker link, so it wins whenever it is playing.
+    pub fn for_route(&self, route: OutputRoute) -> DynamicsSettings {
+        match route {
+            OutputRoute::Headphones => self.headphones,
+            OutputRoute::Speaker | OutputRoute::Both => self.speaker,
+        }
+    }
+}
+/// Layout of the I2S DMA buffer.
+type SinkFormat = Stereo16;
+/// Rate the I2S sink and the DAC clocks run at, every stream is resampled to it.
+pub const OUTPUT_SAMPLE_RATE: u32 = 48_000;
+const DMA_BUFFER_BYTES: usize = 32 * 1024;
+/// 16 bit stereo frames queued in the DMA buffer when it is full.
+const DMA_BUFFER_FRAMES: usize = DMA_BUFFER_BYTES / SinkFormat::BYTES_PER_FRAME;
+/// Most bytes `output_task` moves from the ring to the DMA at a time.
+const OUTPUT_CHUNK_BYTES: usize = 4 * 1024;
+/// How close to empty the DMA buffer gets before `output_task` gives up on
+/// the player and pushes silence.
+const UNDERRUN_MARGIN_MS: u64 = 2;
+/// `player_task` keeps decoding into a slot until less room than this is
+/// left, a stream resampled down fills only part of it in one pass.
+const SLOT_FILL_MARGIN_FRAMES: usize = 64;
+
+pub static REPLAY_GAIN_SETTINGS: Signal<CriticalSectionRawMutex, ReplayGainSettings> =
+    Signal::new();
+pub static EQ_PRESET: Signal<CriticalSectionRawMutex, EqPreset> = Signal::new();
+pub static VOLUME_COMMANDS: Channel<CriticalSectionRawMutex, VolumeCommand, 8> = Channel::new();
+pub static OUTPUT_ROUTE: Signal<CriticalSectionRawMutex, OutputRoute> = Signal::new();
+pub static CROSSFEED_STRENGTH: Signal<CriticalSectionRawMutex, CrossfeedStrength> = Signal::new();
+pub static DYNAMICS_PROFILES: Signal<CriticalSectionRawMutex, DynamicsProfiles> = Signal::new();
+/// Fade lengths for pause, stop, seek and track changes.
+pub static FADE_SETTINGS: Signal<CriticalSectionRawMutex, FadeSettings> = Signal::new();
+pub static AB_REPEAT_COMMANDS: Channel<CriticalSectionRawMutex, AbRepeatCommand, 4> =
+    Channel::new();
+pub static SPECTRUM_CONFIG: Signal<CriticalSectionRawMutex, SpectrumConfig> = Signal::new();
 
 pub fn init(r: DACPeripherals<'static>) -> DACResources {
     info!("Audio init Start!");
@@ -100,10 +195,10 @@ pub fn init(r: DACPeripherals<'static>) -> DACResources {
         .expect("Error setting dac Volume control");
 
     dac_obj
-        .set_dac_left_volume_control(8.0)
+        .set_dac_left_volume_control(DEFAULT_VOLUME_DB)
         .expect("Error setting dac Left volume control");
     dac_obj
-        .set_dac_right_volume_control(8.0)
+        .set_dac_right_volume_control(DEFAULT_VOLUME_DB)
         .expect("Error setting dac Right volume control");
     dac_obj
         .set_headphone_drivers(true, true, HpOutputVoltage::Common1_35V, false)
@@ -140,166 +235,624 @@ pub fn init(r: DACPeripherals<'static>) -> DACResources {
     block_for(Duration::from_millis(1000));
     info!("Audio init End!");
     DACResources {
+        i2s: I2SResources {
+            i2s_bclk: r.i2s_bclk,
+            i2s_dma: r.i2s_dma,
+            i2s_dout: r.i2s_dout,
+            i2s_module: r.i2s_module,
+            i2s_ws: r.i2s_ws,
+        },
         tlv_obj: dac_obj,
-        i2s_bclk: r.i2s_bclk,
-        i2s_dma: r.i2s_dma,
-        i2s_dout: r.i2s_dout,
-        i2s_module: r.i2s_module,
-        i2s_ws: r.i2s_ws,
     }
 }
 
-struct I2SResources {
-    i2s_tx_writer: I2sTx<'static, Blocking>,
-    dma_tx_buf: &'static [u8; 32 * 1024],
+/// Writes the next soft-step of the hardware volume, if there is one to take.
+fn step_hardware_volume(dac_obj: &mut TLV320DAC3100<I2c<'static, Blocking>>, volume: &mut Volume) {
+    if let Some(hardware_volume) = volume.next_hardware_step() {
+        info!("Volume: {}", defmt::Debug2Format(&hardware_volume));
+        apply_hardware_volume(dac_obj, hardware_volume);
+    }
 }
 
-#[embassy_executor::task]
-pub async fn player_task(dac_peripherals: DACResources) {
-    info!("AUDIOTASK: Audio Started");
+/// Resolves when the next hardware volume soft-step is due, never once the
+/// hardware has reached the target.
+async fn volume_step_due(stepping: bool) {
+    if stepping {
+        Timer::after_millis(SOFT_STEP_INTERVAL_MS).await;
+    } else {
+        core::future::pending::<()>().await;
+    }
+}
 
+fn apply_hardware_volume(
+    dac_obj: &mut TLV320DAC3100<I2c<'static, Blocking>>,
+    hardware_volume: HardwareVolume,
+) {
+    let HardwareVolume {
+        digital_db,
+        analog_attenuation_step,
+        muted,
+    } = hardware_volume;
+    let result = dac_obj
+        .set_dac_volume_control(muted, muted, VolumeControl::IndependentChannels)
+        .and_then(|_| dac_obj.set_dac_left_volume_control(digital_db))
+        .and_then(|_| dac_obj.set_dac_right_volume_control(digital_db))
+        .and_then(|_| dac_obj.set_left_analog_volume_to_hpl(true, analog_attenuation_step))
+        .and_then(|_| dac_obj.set_right_analog_volume_to_hpr(true, analog_attenuation_step))
+        .and_then(|_| dac_obj.set_left_analog_volume_to_spk(true, analog_attenuation_step));
+    if let Err(e) = result {
+        info!("Error setting volume: {}", defmt::Debug2Format(&e));
+    }
+}
+
+fn apply_output_route(dac_obj: &mut TLV320DAC3100<I2c<'static, Blocking>>, route: OutputRoute) {
+    let headphones = route != OutputRoute::Speaker;
+    let speaker = route != OutputRoute::Headphones;
+    // The hpl flag is "unmuted", the hpr and spk flags are "muted".
+    let result = dac_obj
+        .set_hpl_driver(0, headphones)
+        .and_then(|_| dac_obj.set_hpr_driver(0, !headphones))
+        .and_then(|_| dac_obj.set_class_d_spk_driver(OutputStage::Gain6dB, !speaker));
+    if let Err(e) = result {
+        info!("Error setting output route: {}", defmt::Debug2Format(&e));
+    }
+}
+
+/// Handles to the stages of the playback chain.
+#[derive(Clone, Copy)]
+struct PlaybackStages {
+    replay_gain: StageId<ReplayGain>,
+    resampler: StageId<Resampler>,
+    time_stretch: StageId<TimeStretch>,
+    equalizer: StageId<Equalizer>,
+    crossfeed: StageId<Crossfeed>,
+    volume: StageId<Volume>,
+    dynamics: StageId<Dynamics>,
+    level_meter: StageId<LevelMeter>,
+}
+
+impl PlaybackStages {
+    /// Adds the playback stages to `chain` in field order, mapping the
+    /// result onto the sink layout before the meter.
+    fn build(chain: &mut DspChain) -> Self {
+        let replay_gain = chain.push(ReplayGain::new(ReplayGainSettings::default()));
+        let resampler = chain.push(Resampler::new(OUTPUT_SAMPLE_RATE));
+        let time_stretch = chain.push(TimeStretch::new(1.0));
+        let equalizer = chain.push(Equalizer::new(EqPreset::flat()));
+        let crossfeed = chain.push(Crossfeed::new(CrossfeedStrength::default()));
+        let volume = chain.push(Volume::new(DEFAULT_VOLUME_DB));
+        let dynamics = chain.push(Dynamics::new(
+            DynamicsProfiles::default().for_route(OutputRoute::default()),
+        ));
+        chain.push(ChannelMap::new(SinkFormat::LAYOUT));
+        let level_meter = chain.push(LevelMeter::new(OUTPUT_SAMPLE_RATE));
+        Self {
+            replay_gain,
+            resampler,
+            time_stretch,
+            equalizer,
+            crossfeed,
+            volume,
+            dynamics,
+            level_meter,
+        }
+    }
+}
+
+/// Decoder frame that is coming out of the DAC right now, the decoder runs
+/// ahead of it by the DSP latency and whatever is queued in the ring and the
+/// DMA buffer.
+fn audible_position(decoder: &Decoder, dsp_chain: &DspChain, stages: PlaybackStages) -> u64 {
+    let output_frames = (dsp_chain.latency_frames()
+        + PCM_RING.len() / SinkFormat::CHANNELS
+        + DMA_BUFFER_FRAMES) as f32;
+    let decoder_frames =
+        output_frames * dsp_chain[stages.time_stretch].speed() * decoder.sample_rate() as f32
+            / dsp_chain[stages.resampler].output_rate() as f32;
+    decoder
+        .current_pcm_frame()
+        .saturating_sub(decoder_frames as u64)
+}
+
+fn position_ms(decoder: &Decoder, dsp_chain: &DspChain, stages: PlaybackStages) -> u64 {
+    audible_position(decoder, dsp_chain, stages) * 1000 / decoder.sample_rate() as u64
+}
+
+fn seek(decoder: &mut Decoder, dsp_chain: &mut DspChain, position_ms: u64) {
+    let frame = (position_ms * decoder.sample_rate() as u64 / 1000).min(decoder.total_pcm_frames());
+    if decoder.seek_to_pcm_frame(frame) {
+        // Nothing buffered in the chain belongs next to the new position.
+        dsp_chain.reset();
+    } else {
+        info!("Seek to {} ms failed", position_ms);
+    }
+}
+
+/// Publishes the queue unless it is what was last published, most commands
+/// leave it alone.
+fn publish_queue(player: &Player) {
+    let tracks = player.queue().tracks();
+    PLAYER_QUEUE.sender().send_if_modified(|published| {
+        if published.as_deref() == Some(tracks) {
+            return false;
+        }
+        *published = Some(tracks.iter().copied().collect());
+        true
+    });
+}
+
+fn publish_resume_point(
+    player: &Player,
+    dsp_chain: &DspChain,
+    stages: PlaybackStages,
+    position_ms: u64,
+) {
+    ResumePoint {
+        current: player.queue().current_index(),
+        position_ms,
+        volume_db: dsp_chain[stages.volume].volume_db(),
+        state: player.state(),
+    }
+    .publish();
+}
+
+/// Handles player and volume commands while nothing is loaded, stepping the
+/// hardware volume, until a command loads a track.
+/// Returns it with where in it to start, in ms.
+async fn wait_for_track(
+    dac_obj: &mut TLV320DAC3100<I2c<'static, Blocking>>,
+    player: &mut Player,
+    dsp_chain: &mut DspChain,
+    stages: PlaybackStages,
+) -> (TrackRef, u64) {
+    loop {
+        let command = match select3(
+            PLAYER_COMMANDS.receive(),
+            VOLUME_COMMANDS.receive(),
+            volume_step_due(dsp_chain[stages.volume].is_stepping()),
+        )
+        .await
+        {
+            Either3::First(command) => command,
+            Either3::Second(volume_command) => {
+                dsp_chain[stages.volume].handle_command(volume_command);
+                step_hardware_volume(dac_obj, &mut dsp_chain[stages.volume]);
+                continue;
+            }
+            Either3::Third(()) => {
+                step_hardware_volume(dac_obj, &mut dsp_chain[stages.volume]);
+                continue;
+            }
+        };
+        let action = player.handle(command, 0);
+        publish_queue(player);
+        match action {
+            Some(PlayerAction::Load(track)) => return (track, 0),
+            Some(PlayerAction::LoadAt { track, position_ms }) => return (track, position_ms),
+            Some(PlayerAction::SetVolume(volume_db)) => {
+                dsp_chain[stages.volume].handle_command(VolumeCommand::Set(volume_db));
+            }
+            Some(PlayerAction::Unload | PlayerAction::Seek { .. } | PlayerAction::SetSpeed(_))
+            | None => {}
+        }
+        publish_resume_point(player, dsp_chain, stages, 0);
+    }
+}
+
+/// Drains [`PCM_RING`] into the I2S DMA, runs on the PRO core.
+#[embassy_executor::task]
+pub async fn output_task(i2s: I2SResources) {
+    info!("AUDIOTASK: Output Started");
     let i2s_driver = I2s::new(
-        dac_peripherals.i2s_module,
-        dac_peripherals.i2s_dma,
+        i2s.i2s_module,
+        i2s.i2s_dma,
         I2SConfig::new_tdm_philips()
             .with_bit_order(esp_hal::i2s::master::BitOrder::MsbFirst)
-            .with_sample_rate(Rate::from_khz(48)),
+            .with_sample_rate(Rate::from_hz(OUTPUT_SAMPLE_RATE)),
     )
-    .unwrap();
-    let (_, _, dma_tx_buf, dma_tx_desc) = dma_circular_buffers!(0, 32 * 1024);
-    let i2s_tx_writer = i2s_driver
+    .unwrap()
+    .into_async();
+    let (_, _, dma_tx_buf, dma_tx_desc) = dma_circular_buffers!(0, DMA_BUFFER_BYTES);
+    let mut i2s_tx_writer = i2s_driver
         .i2s_tx
-        .with_bclk(dac_peripherals.i2s_bclk)
-        .with_dout(dac_peripherals.i2s_dout)
-        .with_ws(dac_peripherals.i2s_ws)
+        .with_bclk(i2s.i2s_bclk)
+        .with_dout(i2s.i2s_dout)
+        .with_ws(i2s.i2s_ws)
         .build(dma_tx_desc);
-    let _index = 0;
-    dma_tx_buf.fill(1);
-    let mut i2s_resources = I2SResources {
-        i2s_tx_writer,
-        dma_tx_buf,
+    dma_tx_buf.fill(0);
+    i2s_tx_writer
+        .apply_config(
+            &UnitConfig::new_tdm_philips()
+                .with_channels(SinkFormat::channels())
+                .with_data_format(SinkFormat::data_format())
+                // Streams are resampled, so the sink format never changes.
+                .with_sample_rate(Rate::from_hz(OUTPUT_SAMPLE_RATE)),
+        )
+        .unwrap();
+    info!("Configured the I2STx Writer");
+    // The transfer owns the I2S TX and runs for as long as the task does.
+    let mut transfer = i2s_tx_writer.write_dma_circular_async(dma_tx_buf).unwrap();
+
+    // Fades what comes out of the ring as it goes into the DMA, so a closed
+    // ring is heard at once. Silent until the player opens the ring.
+    let sink_format = StreamFormat {
+        sample_rate: OUTPUT_SAMPLE_RATE,
+        layout: SinkFormat::LAYOUT,
     };
+    let mut fade = Fade::new(OUTPUT_SAMPLE_RATE, FadeSettings::default());
+    fade.mute();
+    // Fed after the fade, so it shows what is heard rather than what was
+    // decoded a ring's length ago.
+    let mut spectrum = SpectrumAnalyzer::new(SpectrumConfig::default(), OUTPUT_SAMPLE_RATE);
+    // Zeros pushed since the ring ran dry or went silent. Once they fill the
+    // whole DMA buffer it only replays silence and the task can stop feeding it.
+    let mut silent_bytes = 0;
+    loop {
+        // Waits for the DMA to free up room, every other task runs meanwhile.
+        let room = transfer
+            .available()
+            .await
+            .inspect_err(|e| info!("DMAError: {}", e))
+            .unwrap();
+        let queued_bytes = DMA_BUFFER_BYTES.saturating_sub(room);
+        BUFFER_HEALTH.record_fill(PCM_RING.len(), queued_bytes);
+        PCM_RING.drop_flushed();
+        if FADE_SETTINGS.signaled() {
+            fade.set_settings(FADE_SETTINGS.wait().await);
+        }
+        if SPECTRUM_CONFIG.signaled() {
+            spectrum.set_config(SPECTRUM_CONFIG.wait().await);
+        }
+        if PCM_RING.is_open() {
+            fade.fade_in();
+        } else {
+            fade.fade_out();
+        }
+        let audible = !fade.is_silent();
+        PCM_RING.set_silent(!audible);
+        if audible && !PCM_RING.is_empty() {
+            silent_bytes = 0;
+            transfer
+                .push_with(|dma| {
+                    let chunk = dma.len().min(OUTPUT_CHUNK_BYTES);
+                    PCM_RING.pop_into::<SinkFormat>(&mut dma[..chunk], |samples| {
+                        let frames = samples.len() / SinkFormat::CHANNELS;
+                        let mut buffer = AudioBuffer::new(samples, frames, sink_format);
+                        fade.apply(&mut buffer);
+                        spectrum.push(&buffer);
+                    })
+                })
+                .await
+                .inspect_err(|e| info!("DMAError: {}", e))
+                .unwrap();
+            continue;
+        }
+        if silent_bytes >= DMA_BUFFER_BYTES {
+            info!("Output idle, waiting for the player");
+            PCM_RING.wait_for_producer().await;
+            continue;
+        }
+        if audible {
+            // The DMA still has audio queued, give the player until just
+            // before it runs out to catch up.
+            let queued_frames = queued_bytes / SinkFormat::BYTES_PER_FRAME;
+            let until_dry =
+                Duration::from_micros(queued_frames as u64 * 1_000_000 / OUTPUT_SAMPLE_RATE as u64)
+                    .checked_sub(Duration::from_millis(UNDERRUN_MARGIN_MS))
+                    .unwrap_or(Duration::from_ticks(0));
+            if with_timeout(until_dry, PCM_RING.wait_for_data())
+                .await
+                .is_ok()
+            {
+                continue;
+            }
+        }
+        // Ran dry while audible, ramps the last frame down instead of
+        // stopping dead. Silent, fills the DMA with zeros.
+        let pushed = transfer
+            .push_with(|dma| {
+                let chunk = dma.len().min(OUTPUT_CHUNK_BYTES);
+                if !audible {
+                    dma[..chunk].fill(0);
+                    return chunk;
+                }
+                let mut tail = [0i16; OUTPUT_CHUNK_BYTES / SinkFormat::BYTES_PER_SAMPLE];
+                let frames = chunk / SinkFormat::BYTES_PER_FRAME;
+                let mut buffer =
+                    AudioBuffer::new(&mut tail[..frames * SinkFormat::CHANNELS], 0, sink_format);
+                fade.tail(&mut buffer);
+                let samples = buffer.samples();
+                for (&sample, out) in samples
+                    .iter()
+                    .zip(dma.chunks_exact_mut(SinkFormat::BYTES_PER_SAMPLE))
+                {
+                    SinkFormat::pack(sample, out);
+                }
+                dma[samples.len() * SinkFormat::BYTES_PER_SAMPLE..chunk].fill(0);
+                chunk
+            })
+            .await
+            .inspect_err(|e| info!("DMAError: {}", e))
+            .unwrap();
+        silent_bytes += pushed;
+        if audible {
+            BUFFER_HEALTH.record_underrun(pushed / SinkFormat::BYTES_PER_FRAME);
+            info!(
+                "Output underrun, pushed {} frames of fade out and silence",
+                pushed / SinkFormat::BYTES_PER_FRAME
+            );
+        }
+    }
+}
+
+/// Decodes and processes the current track into [`PCM_RING`], runs on the
+/// APP core so nothing on the PRO core can hold it up.
+#[embassy_executor::task]
+pub async fn player_task(mut dac_obj: TLV320DAC3100<I2c<'static, Blocking>>) {
+    info!("AUDIOTASK: Audio Started");
+    let mut dsp_chain = DspChain::new();
+    let stages = PlaybackStages::build(&mut dsp_chain);
+    let mut output_route = OutputRoute::default();
+    let mut dynamics_profiles = DynamicsProfiles::default();
+    // Crossfeed only makes sense when nothing but the headphones is playing.
+    dsp_chain[stages.crossfeed].set_bypass(output_route != OutputRoute::Headphones);
+
+    let mut player = Player::new();
+    // The track to load next and where in it to start, in ms.
+    let mut next_track: Option<(TrackRef, u64)> = None;
 
     loop {
-        info!("Waiting for the Decoder obj to come in");
-        let FileInfo {
-            file_name,
-            file_bytes,
-        } = AUDIO_DECODER.wait().await;
+        let (
+            TrackRef {
+                file:
+                    FileInfo {
+                        file_name,
+                        file_bytes,
+                    },
+                cue_track,
+                speed,
+            },
+            start_ms,
+        ) = match next_track.take() {
+            Some(track) => track,
+            None => {
+                PlaybackStatus::default().publish();
+                BUFFER_HEALTH.set_streaming(false);
+                info!("Waiting for a track to load");
+                wait_for_track(&mut dac_obj, &mut player, &mut dsp_chain, stages).await
+            }
+        };
         info!("Got the FileInfo obj");
+        if let Some(cue_track) = cue_track {
+            // Nothing reads CUE sheets yet, so the whole file plays.
+            info!("Playing all of {} for CUE track {}", file_name, cue_track);
+        }
         let mut decoder = Decoder::new(file_name, file_bytes);
+        let stream_format = decoder.stream_format();
+        // Drops what the stages hold of the last track.
+        dsp_chain.configure(stream_format);
+        dsp_chain[stages.time_stretch].set_speed(speed.unwrap_or(1.0));
+        if start_ms > 0 {
+            seek(&mut decoder, &mut dsp_chain, start_ms);
+        }
+        let mut ab_repeat = AbRepeat::new(stream_format.sample_rate);
+        let mut status = PlaybackStatus::for_track(file_name, file_bytes.len(), &decoder);
+        status.publish();
+        let mut replay_gain_info = decoder.metadata().replay_gain;
+        if replay_gain_info.is_empty() {
+            // Untagged, fall back to our own scan of the file.
+            match scanner::cached_loudness(file_name, file_bytes.len()) {
+                Some(loudness) => replay_gain_info = loudness.replay_gain_info(),
+                None => {
+                    let _ = scanner::LOUDNESS_SCAN_REQUESTS.try_send(FileInfo {
+                        file_name,
+                        file_bytes,
+                    });
+                }
+            }
+        }
+        dsp_chain[stages.replay_gain].set_track_info(replay_gain_info);
+        info!(
+            "ReplayGain: {}, linear gain: {}",
+            defmt::Debug2Format(&replay_gain_info),
+            dsp_chain[stages.replay_gain].linear_gain()
+        );
         match decoder {
             codec::codec::Decoder::FLAC(ref this_meta) => {
                 // pos = this_meta.metadata.audio_frame_start_pos;
                 info!("Metadata: {}", defmt::Debug2Format(&this_meta.metadata));
-                i2s_resources
-                    .i2s_tx_writer
-                    .apply_config(
-                        &UnitConfig::new_tdm_philips()
-                            .with_channels(Channels::STEREO)
-                            .with_data_format(DataFormat::Data16Channel16)
-                            .with_sample_rate(Rate::from_hz(
-                                // this_meta.metadata.stream_info.unwrap().sampleRate,
-                                48000, // TODO: Change this to be variable
-                            )), // .with_data_format()
-                    )
-                    .unwrap();
-
-                info!("Configured the I2STx Writer");
             }
         }
-        let mut transfer = i2s_resources
-            .i2s_tx_writer
-            .write_dma_circular(i2s_resources.dma_tx_buf)
-            .unwrap();
-
-        const NUM_SAMPLES_PER_CALL: usize = 1024;
-        let mut samples_to_write = [0_i16; NUM_SAMPLES_PER_CALL];
-        let mut frame_size_bytes: usize;
+        // Seek, Load or Unload waiting for the output to fade out.
+        let mut pending: Option<PlayerAction> = None;
 
         info!("Starting the buff filler");
-        let mut last_player_state = PlayPauseState::Pause;
-        while !AUDIO_DECODER.signaled() {
+        'track: loop {
+            // Open while playing. The output fades a closed ring out and holds
+            // it, and fades in whatever it opens on, apart from a track that
+            // runs straight on from one that played to its end.
+            let open = pending.is_none() && player.state() == PlaybackState::Playing;
+            PCM_RING.set_open(open);
+            // Wakes up for the next volume soft-step as well, the loop takes
+            // one per pass.
+            let volume_stepping = dsp_chain[stages.volume].is_stepping();
+            if !open && !PCM_RING.is_silent() {
+                select3(
+                    PCM_RING.wait_silent(),
+                    PLAYER_COMMANDS.ready_to_receive(),
+                    volume_step_due(volume_stepping),
+                )
+                .await;
+            } else if !open && pending.is_none() {
+                BUFFER_HEALTH.set_streaming(false);
+                info!("Paused, waiting for a command");
+                select3(
+                    PLAYER_COMMANDS.ready_to_receive(),
+                    VOLUME_COMMANDS.ready_to_receive(),
+                    volume_step_due(volume_stepping),
+                )
+                .await;
+            }
             // info!("AUDIOTASK: isEOF:{}", decoder_result.is_eof);
-            let current_play_pause_state = if PLAY_PAUSE_STATE.signaled() {
-                last_player_state = PLAY_PAUSE_STATE.wait().await;
-                last_player_state
-            } else {
-                last_player_state
-            };
-            info!(
-                "Current State:{}",
-                defmt::Debug2Format(&current_play_pause_state)
-            );
-            match current_play_pause_state {
-                PlayPauseState::Play => {
-                    let frames_to_read = (NUM_SAMPLES_PER_CALL / 2) as u64;
-                    let decoder_meta =
-                        decoder.get_pcm_samples(frames_to_read, &mut samples_to_write);
-                    info! {"FramesRead:{}",decoder_meta.framesRead};
-                    info! {"currentSampleIdx:{}",decoder_meta.currentPCMFrameIdx};
-                    if decoder_meta.framesRead == 0 {
-                        info!("EOF breaking out");
-                        break;
+            if REPLAY_GAIN_SETTINGS.signaled() {
+                dsp_chain[stages.replay_gain].set_settings(REPLAY_GAIN_SETTINGS.wait().await);
+                info!(
+                    "ReplayGain linear gain: {}",
+                    dsp_chain[stages.replay_gain].linear_gain()
+                );
+            }
+            if EQ_PRESET.signaled() {
+                dsp_chain[stages.equalizer].set_preset(EQ_PRESET.wait().await);
+                info!(
+                    "EQ preset: {}",
+                    defmt::Debug2Format(dsp_chain[stages.equalizer].preset())
+                );
+            }
+            if OUTPUT_ROUTE.signaled() {
+                output_route = OUTPUT_ROUTE.wait().await;
+                apply_output_route(&mut dac_obj, output_route);
+                dsp_chain[stages.crossfeed].set_bypass(output_route != OutputRoute::Headphones);
+                dsp_chain[stages.dynamics].set_settings(dynamics_profiles.for_route(output_route));
+                info!("Output route: {}", defmt::Debug2Format(&output_route));
+            }
+            if DYNAMICS_PROFILES.signaled() {
+                dynamics_profiles = DYNAMICS_PROFILES.wait().await;
+                dsp_chain[stages.dynamics].set_settings(dynamics_profiles.for_route(output_route));
+            }
+            if CROSSFEED_STRENGTH.signaled() {
+                dsp_chain[stages.crossfeed].set_strength(CROSSFEED_STRENGTH.wait().await);
+            }
+            while let Ok(ab_repeat_command) = AB_REPEAT_COMMANDS.try_receive() {
+                ab_repeat.handle_command(
+                    ab_repeat_command,
+                    audible_position(&decoder, &dsp_chain, stages),
+                );
+                info!(
+                    "A-B repeat: {}, repeats: {}",
+                    ab_repeat.region(),
+                    ab_repeat.repeats()
+                );
+            }
+            while let Ok(command) = PLAYER_COMMANDS.try_receive() {
+                let action = player.handle(command, position_ms(&decoder, &dsp_chain, stages));
+                publish_queue(&player);
+                match action {
+                    Some(PlayerAction::SetVolume(volume_db)) => {
+                        dsp_chain[stages.volume].handle_command(VolumeCommand::Set(volume_db));
                     }
+                    Some(PlayerAction::SetSpeed(speed)) => {
+                        dsp_chain[stages.time_stretch].set_speed(speed);
+                        info!("Playback speed: {}", dsp_chain[stages.time_stretch].speed());
+                    }
+                    // A seek never replaces a pending track change.
+                    Some(PlayerAction::Seek { .. })
+                        if matches!(
+                            pending,
+                            Some(
+                                PlayerAction::Load(_)
+                                    | PlayerAction::LoadAt { .. }
+                                    | PlayerAction::Unload
+                            )
+                        ) => {}
+                    Some(action) => pending = Some(action),
+                    None => {}
                 }
-                PlayPauseState::Pause => {
-                    // samples_to_write.extend(repeat_n(0, 1000));
-                    // samples_to_write.copy_from_slice(&[0;16*1024]);
-                    // samples_to_write.fill(0_i16);
-                    embassy_time::Timer::after(Duration::from_nanos(10)).await;
-                    // info!("In Pause State: Filling Sending filled zeros");
+            }
+            let open = pending.is_none() && player.state() == PlaybackState::Playing;
+            PCM_RING.set_open(open);
+            if !open
+                && PCM_RING.is_silent()
+                && let Some(action) = pending.take()
+            {
+                // Nothing held in the ring goes with what comes next.
+                PCM_RING.flush().await;
+                match action {
+                    PlayerAction::Load(track) => {
+                        next_track = Some((track, 0));
+                        break 'track;
+                    }
+                    PlayerAction::LoadAt { track, position_ms } => {
+                        next_track = Some((track, position_ms));
+                        break 'track;
+                    }
+                    PlayerAction::Unload => break 'track,
+                    PlayerAction::Seek { position_ms } => {
+                        seek(&mut decoder, &mut dsp_chain, position_ms);
+                        // Back round to fade in at the new position.
+                        continue;
+                    }
+                    PlayerAction::SetVolume(_) | PlayerAction::SetSpeed(_) => {}
                 }
             }
-            frame_size_bytes = samples_to_write.len() * 2;
-
-            // info!(
-            //     "AUDIOTASK: Bytes Contents: {}",
-            //     defmt::Debug2Format(&samples_to_write)
-            // );
-            info!("AUDIOTASK: Bytes to Write: {}", frame_size_bytes);
-            let mut chunk_start_index = 0;
-            let mut chunk_end_index;
-
-            loop {
-                // if true{break;}
-                let dma_available_bytes = transfer
-                    .available()
-                    .inspect_err(|e: &dma::DmaError| info!("DMAError: {}", e))
-                    .unwrap();
-                if dma_available_bytes == 0 {
-                    // info!("DMA full");
-                    // embassy_time::Timer::after(Duration::from_nanos(10)).await;
-                } else {
-                    info!("AUDIOTASK: Available Bytes: {}", dma_available_bytes);
-                    // info!("Writing to the DMA");
-                    chunk_end_index = min(
-                        frame_size_bytes / 2,
-                        dma_available_bytes / 2 + chunk_start_index,
-                    );
-                    info!("AUDIOTASK: startIDX:{}", chunk_start_index);
-                    info!("AUDIOTASK: endIDX:{}", chunk_end_index);
-                    // info!(
-                    //     "AUDIOTASK: Writing:{}",
-                    //     samples_to_write[chunk_start_index..chunk_end_index]
-                    // );
-                    transfer
-                        .push(unsafe {
-                            from_raw_parts(
-                                samples_to_write[chunk_start_index..chunk_end_index]
-                                    .as_ptr()
-                                    .cast(),
-                                (chunk_end_index - chunk_start_index) * 2,
-                            )
-                        })
-                        .inspect_err(|e| info!("DMAError: {}", e))
-                        .unwrap();
-                    chunk_start_index = chunk_end_index;
-
-                    if chunk_start_index >= frame_size_bytes / 2 {
-                        break;
+            while let Ok(volume_command) = VOLUME_COMMANDS.try_receive() {
+                dsp_chain[stages.volume].handle_command(volume_command);
+            }
+            step_hardware_volume(&mut dac_obj, &mut dsp_chain[stages.volume]);
+            publish_resume_point(
+                &player,
+                &dsp_chain,
+                stages,
+                position_ms(&decoder, &dsp_chain, stages),
+            );
+            // Before the paused and stopped passes skip the decode, so those
+            // states are published too.
+            status.update(
+                player.state(),
+                audible_position(&decoder, &dsp_chain, stages),
+            );
+            if !open {
+                continue;
+            }
+            // Decoded and processed in place, right where the output reads it,
+            // one pass after another behind what is already in the slot.
+            let mut slot = PCM_RING.claim().await;
+            let mut filled = 0;
+            let mut decoder_done = false;
+            while (SLOT_SAMPLES - filled) / SinkFormat::CHANNELS >= SLOT_FILL_MARGIN_FRAMES {
+                let mut position = decoder.current_pcm_frame();
+                if let Some(loop_start) = ab_repeat.loop_target(position) {
+                    if decoder.seek_to_pcm_frame(loop_start) {
+                        position = loop_start;
+                    } else {
+                        info!("A-B repeat: seek to {} failed", loop_start);
                     }
                 }
+                let mut block = Block::new(&mut slot[filled..], 0, stream_format);
+                // Leaves room for every frame once the chain has mapped them
+                // onto the sink layout.
+                block.limit_frames((SLOT_SAMPLES - filled) / SinkFormat::CHANNELS);
+                let capacity_frames = block.capacity_frames();
+                let frames_to_read = ab_repeat.frames_to_read(
+                    position,
+                    min(dsp_chain.input_frames_for(capacity_frames), capacity_frames) as u64,
+                );
+                let decode_start = Instant::now();
+                let decoder_meta = decoder.read_frames(&mut block, frames_to_read);
+                BUFFER_HEALTH.record_decode(decode_start.elapsed().as_micros() as u32);
+                if decoder_meta.framesRead == 0 {
+                    decoder_done = true;
+                    break;
+                }
+                ab_repeat.apply_fades(&mut block, position);
+                dsp_chain.process(&mut block);
+                filled += block.samples().len();
+            }
+            let clips = dsp_chain.take_clipped_samples();
+            dsp_chain[stages.level_meter].record_clips(clips);
+            BUFFER_HEALTH.set_streaming(true);
+            slot.commit(filled);
+            if decoder_done {
+                if decoder.current_pcm_frame() < decoder.total_pcm_frames() {
+                    // Faded out and dropped like a skip rather than cut off.
+                    info!("Decode error, skipping the rest of the track");
+                    pending = Some(player.track_ended().unwrap_or(PlayerAction::Unload));
+                    continue;
+                }
+                info!("EOF breaking out");
+                if let Some(PlayerAction::Load(track)) = player.track_ended() {
+                    next_track = Some((track, 0));
+                }
+                break 'track;
             }
         }
     }
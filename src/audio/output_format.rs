@@ -0,0 +1,41 @@
+//! Sample layout of the I2S DMA buffer.
+//!
+//! `output_task` configures the I2S unit and packs the DMA buffer from the
+//! same [`OutputFormat`], so the two can never disagree about the layout.
+
+use esp_hal::i2s::master::{Channels, DataFormat};
+
+use crate::audio::pcm::{ChannelLayout, Sample};
+
+pub trait OutputFormat {
+    /// What each sample goes out as.
+    type Sample: Sample;
+    const LAYOUT: ChannelLayout;
+    const CHANNELS: usize = Self::LAYOUT.channels();
+    const BYTES_PER_SAMPLE: usize = <Self::Sample as Sample>::FORMAT.bytes();
+    const BYTES_PER_FRAME: usize = Self::CHANNELS * Self::BYTES_PER_SAMPLE;
+
+    fn channels() -> Channels;
+    fn data_format() -> DataFormat;
+
+    /// Converts one sample and packs it into `BYTES_PER_SAMPLE` bytes.
+    fn pack<S: Sample>(sample: S, bytes: &mut [u8]) {
+        sample.convert::<Self::Sample>().write_le(bytes);
+    }
+}
+
+/// 16 bit interleaved stereo, what the DAC is clocked for.
+pub struct Stereo16;
+
+impl OutputFormat for Stereo16 {
+    type Sample = i16;
+    const LAYOUT: ChannelLayout = ChannelLayout::Stereo;
+
+    fn channels() -> Channels {
+        Channels::STEREO
+    }
+
+    fn data_format() -> DataFormat {
+        DataFormat::Data16Channel16
+    }
+}
@@ -0,0 +1,480 @@
+//! Typed PCM: sample formats, channel layouts and the buffers that carry them.
+//!
+//! An [`AudioBuffer`] knows its [`StreamFormat`], so callers ask it for frames
+//! and never work out sample counts themselves. Every [`Sample`] type converts
+//! through a common full scale `i32`, which is how the decoder, the DSP chain
+//! and the I2S sink can each use whatever format suits them. Like the DSP
+//! stages this only depends on `core`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    S16,
+    /// 24 bit samples sign extended into 32 bit words.
+    S24In32,
+    S32,
+    F32,
+}
+
+impl SampleFormat {
+    /// Bytes one sample takes in memory.
+    pub const fn bytes(self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24In32 | SampleFormat::S32 | SampleFormat::F32 => 4,
+        }
+    }
+
+    /// Bits of resolution, less than the storage size for `S24In32`.
+    pub const fn bits(self) -> u8 {
+        match self {
+            SampleFormat::S16 => 16,
+            SampleFormat::S24In32 => 24,
+            SampleFormat::S32 | SampleFormat::F32 => 32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    #[default]
+    Stereo,
+    /// Anything wider, interleaved in stream order.
+    Multi(u8),
+}
+
+impl ChannelLayout {
+    /// `None` for no channels or more than [`ChannelLayout::Multi`] holds.
+    pub const fn from_channels(channels: usize) -> Option<Self> {
+        match channels {
+            1 => Some(ChannelLayout::Mono),
+            2 => Some(ChannelLayout::Stereo),
+            3..=255 => Some(ChannelLayout::Multi(channels as u8)),
+            _ => None,
+        }
+    }
+
+    pub const fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Multi(channels) => channels as usize,
+        }
+    }
+
+    /// Speaker of each channel in stream order, by the FLAC/WAVE default
+    /// order for the channel count. Channels past the eighth have none.
+    pub const fn speakers(self) -> &'static [Speaker] {
+        use Speaker::*;
+        match self.channels() {
+            1 => &[Center],
+            2 => &[Left, Right],
+            3 => &[Left, Right, Center],
+            4 => &[Left, Right, SurroundLeft, SurroundRight],
+            5 => &[Left, Right, Center, SurroundLeft, SurroundRight],
+            6 => &[Left, Right, Center, Lfe, SurroundLeft, SurroundRight],
+            7 => &[
+                Left,
+                Right,
+                Center,
+                Lfe,
+                BackCenter,
+                SurroundLeft,
+                SurroundRight,
+            ],
+            _ => &[
+                Left,
+                Right,
+                Center,
+                Lfe,
+                SurroundLeft,
+                SurroundRight,
+                SurroundLeft,
+                SurroundRight,
+            ],
+        }
+    }
+}
+
+/// Where a channel is meant to be played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    Left,
+    Right,
+    Center,
+    Lfe,
+    SurroundLeft,
+    SurroundRight,
+    BackCenter,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub layout: ChannelLayout,
+}
+
+impl StreamFormat {
+    pub const fn channels(&self) -> usize {
+        self.layout.channels()
+    }
+}
+
+impl Default for StreamFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48_000,
+            layout: ChannelLayout::Stereo,
+        }
+    }
+}
+
+/// A 24 bit sample in the low bits of an `i32`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct S24(pub i32);
+
+/// # Safety
+///
+/// The type must be laid out in memory exactly as `FORMAT` says, decoders
+/// write straight into buffers of it.
+pub unsafe trait Sample: Copy + Default {
+    const FORMAT: SampleFormat;
+
+    /// The sample scaled to the full `i32` range.
+    fn to_i32_full_scale(self) -> i32;
+
+    /// Rounds and saturates a full scale `i32` into this format.
+    fn from_i32_full_scale(sample: i32) -> Self;
+
+    /// Little endian, into `FORMAT.bytes()` bytes.
+    fn write_le(self, bytes: &mut [u8]);
+
+    fn convert<T: Sample>(self) -> T {
+        T::from_i32_full_scale(self.to_i32_full_scale())
+    }
+}
+
+/// Drops the low `shift` bits of a full scale sample, rounding to nearest.
+fn round_down(sample: i32, shift: u32) -> i32 {
+    ((sample as i64 + (1 << (shift - 1))) >> shift).min((i32::MAX >> shift) as i64) as i32
+}
+
+unsafe impl Sample for i16 {
+    const FORMAT: SampleFormat = SampleFormat::S16;
+
+    fn to_i32_full_scale(self) -> i32 {
+        (self as i32) << 16
+    }
+
+    fn from_i32_full_scale(sample: i32) -> Self {
+        round_down(sample, 16) as i16
+    }
+
+    fn write_le(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+unsafe impl Sample for S24 {
+    const FORMAT: SampleFormat = SampleFormat::S24In32;
+
+    fn to_i32_full_scale(self) -> i32 {
+        self.0 << 8
+    }
+
+    fn from_i32_full_scale(sample: i32) -> Self {
+        S24(round_down(sample, 8))
+    }
+
+    fn write_le(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+unsafe impl Sample for i32 {
+    const FORMAT: SampleFormat = SampleFormat::S32;
+
+    fn to_i32_full_scale(self) -> i32 {
+        self
+    }
+
+    fn from_i32_full_scale(sample: i32) -> Self {
+        sample
+    }
+
+    fn write_le(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+unsafe impl Sample for f32 {
+    const FORMAT: SampleFormat = SampleFormat::F32;
+
+    fn to_i32_full_scale(self) -> i32 {
+        // `as` saturates, anything past full scale clips.
+        (self * 2_147_483_648.0) as i32
+    }
+
+    fn from_i32_full_scale(sample: i32) -> Self {
+        sample as f32 / 2_147_483_648.0
+    }
+
+    fn write_le(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// Interleaved PCM, backed by a buffer that may be larger than the frames it
+/// currently holds so rate changing stages can grow it.
+pub struct AudioBuffer<'a, S: Sample = i16> {
+    buffer: &'a mut [S],
+    frames: usize,
+    format: StreamFormat,
+    /// Most frames the buffer may hold, whatever the layout.
+    frame_limit: usize,
+}
+
+impl<'a, S: Sample> AudioBuffer<'a, S> {
+    pub fn new(buffer: &'a mut [S], frames: usize, format: StreamFormat) -> Self {
+        let frames = frames.min(buffer.len() / format.channels());
+        Self {
+            buffer,
+            frames,
+            format,
+            frame_limit: usize::MAX,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    pub fn channels(&self) -> usize {
+        self.format.channels()
+    }
+
+    pub fn capacity_frames(&self) -> usize {
+        self.capacity_for(self.format)
+    }
+
+    fn capacity_for(&self, format: StreamFormat) -> usize {
+        (self.buffer.len() / format.channels()).min(self.frame_limit)
+    }
+
+    /// Caps how many frames the buffer may grow to, so a stage that widens
+    /// the layout later on still finds room for all of them.
+    pub fn limit_frames(&mut self, frame_limit: usize) {
+        self.frame_limit = frame_limit;
+        self.frames = self.frames.min(self.capacity_frames());
+    }
+
+    pub fn samples(&self) -> &[S] {
+        &self.buffer[..self.frames * self.format.channels()]
+    }
+
+    pub fn samples_mut(&mut self) -> &mut [S] {
+        &mut self.buffer[..self.frames * self.format.channels()]
+    }
+
+    /// One slice per frame.
+    pub fn frames_iter(&self) -> impl Iterator<Item = &[S]> {
+        self.samples().chunks_exact(self.format.channels())
+    }
+
+    pub fn frames_iter_mut(&mut self) -> impl Iterator<Item = &mut [S]> {
+        let channels = self.format.channels();
+        self.samples_mut().chunks_exact_mut(channels)
+    }
+
+    /// The whole backing buffer, for stages that rewrite the buffer.
+    pub fn buffer_mut(&mut self) -> &mut [S] {
+        self.buffer
+    }
+
+    /// Sets the number of valid frames and the format of the buffer contents,
+    /// after a stage has rewritten them through [`AudioBuffer::buffer_mut`].
+    pub fn set_contents(&mut self, frames: usize, format: StreamFormat) {
+        self.format = format;
+        self.frames = frames.min(self.capacity_for(format));
+    }
+
+    /// Replaces the contents with `source` converted to `S`, as many frames
+    /// as fit.
+    pub fn convert_from<T: Sample>(&mut self, source: &AudioBuffer<'_, T>) {
+        let format = source.format();
+        let frames = source.frames().min(self.capacity_for(format));
+        let samples = frames * format.channels();
+        for (out, &sample) in self.buffer[..samples].iter_mut().zip(source.samples()) {
+            *out = sample.convert();
+        }
+        self.set_contents(frames, format);
+    }
+
+    /// Replaces the contents with the planes of `source` woven together.
+    pub fn interleave_from<T: Sample>(&mut self, source: &PlanarBuffer<'_, T>) {
+        let format = source.format();
+        let frames = source.frames().min(self.capacity_for(format));
+        self.set_contents(frames, format);
+        for channel in 0..format.channels() {
+            for (frame, &sample) in self.frames_iter_mut().zip(source.plane(channel)) {
+                frame[channel] = sample.convert();
+            }
+        }
+    }
+}
+
+/// Planar PCM, one plane of `capacity_frames` samples per channel laid out
+/// back to back in the backing buffer.
+pub struct PlanarBuffer<'a, S: Sample = i16> {
+    buffer: &'a mut [S],
+    frames: usize,
+    format: StreamFormat,
+}
+
+impl<'a, S: Sample> PlanarBuffer<'a, S> {
+    pub fn new(buffer: &'a mut [S], frames: usize, format: StreamFormat) -> Self {
+        let frames = frames.min(buffer.len() / format.channels());
+        Self {
+            buffer,
+            frames,
+            format,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    pub fn capacity_frames(&self) -> usize {
+        self.buffer.len() / self.format.channels()
+    }
+
+    pub fn plane(&self, channel: usize) -> &[S] {
+        let start = channel * self.capacity_frames();
+        &self.buffer[start..start + self.frames]
+    }
+
+    pub fn plane_mut(&mut self, channel: usize) -> &mut [S] {
+        let start = channel * self.capacity_frames();
+        &mut self.buffer[start..start + self.frames]
+    }
+
+    /// Replaces the contents with the channels of `source` pulled apart.
+    pub fn deinterleave_from<T: Sample>(&mut self, source: &AudioBuffer<'_, T>) {
+        let format = source.format();
+        self.format = format;
+        self.frames = source.frames().min(self.capacity_frames());
+        for channel in 0..format.channels() {
+            for (out, frame) in self.plane_mut(channel).iter_mut().zip(source.frames_iter()) {
+                *out = frame[channel].convert();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: StreamFormat = StreamFormat {
+        sample_rate: 48_000,
+        layout: ChannelLayout::Stereo,
+    };
+
+    #[test]
+    fn layouts_cover_one_to_255_channels() {
+        assert_eq!(ChannelLayout::from_channels(0), None);
+        assert_eq!(ChannelLayout::from_channels(1), Some(ChannelLayout::Mono));
+        assert_eq!(ChannelLayout::from_channels(2), Some(ChannelLayout::Stereo));
+        for channels in [3, 8, 255] {
+            let layout = ChannelLayout::from_channels(channels).unwrap();
+            assert_eq!(layout.channels(), channels);
+        }
+        assert_eq!(ChannelLayout::from_channels(256), None);
+    }
+
+    #[test]
+    fn i16_round_trips_through_every_format() {
+        for sample in [i16::MIN, -12_345, -1, 0, 1, 12_345, i16::MAX] {
+            assert_eq!(sample.convert::<S24>().convert::<i16>(), sample);
+            assert_eq!(sample.convert::<i32>().convert::<i16>(), sample);
+            assert_eq!(sample.convert::<f32>().convert::<i16>(), sample);
+        }
+        assert_eq!(i16::MIN.convert::<f32>(), -1.0);
+        assert_eq!(S24(-0x80_0000).convert::<i16>(), i16::MIN);
+    }
+
+    #[test]
+    fn narrowing_rounds_to_nearest_and_saturates() {
+        assert_eq!(i16::from_i32_full_scale(0x7fff), 0);
+        assert_eq!(i16::from_i32_full_scale(0x8000), 1);
+        assert_eq!(i16::from_i32_full_scale(-0x8001), -1);
+        assert_eq!(i16::from_i32_full_scale(i32::MAX), i16::MAX);
+        assert_eq!(i16::from_i32_full_scale(i32::MIN), i16::MIN);
+        assert_eq!(S24::from_i32_full_scale(i32::MAX), S24(0x7f_ffff));
+        assert_eq!(S24::from_i32_full_scale(0x80), S24(1));
+    }
+
+    #[test]
+    fn f32_past_full_scale_clips() {
+        assert_eq!(1.0f32.convert::<i16>(), i16::MAX);
+        assert_eq!(2.0f32.convert::<i32>(), i32::MAX);
+        assert_eq!((-2.0f32).convert::<i16>(), i16::MIN);
+        assert_eq!(0.5f32.convert::<i16>(), 16_384);
+    }
+
+    #[test]
+    fn samples_are_written_little_endian_at_their_size() {
+        let mut bytes = [0; 4];
+        0x1234i16.write_le(&mut bytes[..SampleFormat::S16.bytes()]);
+        assert_eq!(bytes[..2], [0x34, 0x12]);
+        S24(-2).write_le(&mut bytes[..SampleFormat::S24In32.bytes()]);
+        assert_eq!(bytes, [0xfe, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn buffers_count_frames_not_samples() {
+        let mut samples = [0i16; 9];
+        let buffer = AudioBuffer::new(&mut samples, 10, STEREO);
+        assert_eq!(buffer.frames(), 4);
+        assert_eq!(buffer.capacity_frames(), 4);
+        assert_eq!(buffer.samples().len(), 8);
+        assert_eq!(buffer.frames_iter().count(), 4);
+    }
+
+    #[test]
+    fn convert_from_keeps_the_format_and_as_many_frames_as_fit() {
+        let mut wide = [0i32, i32::MAX, i32::MIN, 0x8000];
+        let source = AudioBuffer::new(&mut wide, 2, STEREO);
+        let mut narrow = [0i16; 2];
+        let mut buffer = AudioBuffer::new(&mut narrow, 0, StreamFormat::default());
+        buffer.convert_from(&source);
+        assert_eq!(buffer.frames(), 1);
+        assert_eq!(buffer.samples(), [0, i16::MAX]);
+    }
+
+    #[test]
+    fn planar_and_interleaved_round_trip() {
+        let mut interleaved = [1i16, -1, 2, -2, 3, -3];
+        let source = AudioBuffer::new(&mut interleaved, 3, STEREO);
+        let mut planes = [0i32; 8];
+        let mut planar = PlanarBuffer::new(&mut planes, 0, STEREO);
+        planar.deinterleave_from(&source);
+        assert_eq!(planar.frames(), 3);
+        assert_eq!(planar.plane(0), [1 << 16, 2 << 16, 3 << 16]);
+        assert_eq!(planar.plane(1), [-1 << 16, -2 << 16, -3 << 16]);
+        let mut out = [0i16; 6];
+        let mut buffer = AudioBuffer::new(&mut out, 0, STEREO);
+        buffer.interleave_from(&planar);
+        assert_eq!(buffer.samples(), [1, -1, 2, -2, 3, -3]);
+    }
+}
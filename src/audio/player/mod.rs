@@ -0,0 +1,547 @@
+//! Player control state machine.
+//!
+//! [`Player`] owns the transport state and the [`Queue`] and turns every
+//! [`PlayerCommand`] into a state change plus at most one [`PlayerAction`] for
+//! `player_task` to carry out on the decoder. It does not touch the decoder
+//! or the hardware itself, so every transition can be driven on the host.
+
+pub mod queue;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::watch::{Receiver, Watch};
+
+use crate::audio::status::PlaybackState;
+pub use queue::{FileInfo, QUEUE_CAPACITY, Queue, ShuffleMode, TrackRef};
+
+pub static PLAYER_COMMANDS: Channel<CriticalSectionRawMutex, PlayerCommand, 8> = Channel::new();
+/// Number of tasks that can hold a [`PLAYER_QUEUE`] receiver at the same time.
+pub const QUEUE_RECEIVERS: usize = 2;
+/// The player queue, republished whenever a player command changes it.
+pub static PLAYER_QUEUE: Watch<
+    CriticalSectionRawMutex,
+    heapless::Vec<TrackRef, QUEUE_CAPACITY>,
+    QUEUE_RECEIVERS,
+> = Watch::new();
+pub type QueueReceiver = Receiver<
+    'static,
+    CriticalSectionRawMutex,
+    heapless::Vec<TrackRef, QUEUE_CAPACITY>,
+    QUEUE_RECEIVERS,
+>;
+
+/// Previous restarts the current track instead when it is further in than this.
+pub const PREVIOUS_RESTART_MS: u64 = 3_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Plays the current track over and over, Next and Previous still move.
+    One,
+    /// Starts the queue over after the last track, reshuffled when shuffling.
+    All,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PlayerCommand {
+    Play,
+    Pause,
+    Toggle,
+    Stop,
+    Next,
+    Previous,
+    Seek {
+        position_ms: u64,
+    },
+    /// Volume in dB, see [`crate::audio::volume`].
+    SetVolume(f32),
+    /// Playback speed of the current track, kept with it in the queue so it
+    /// plays at that speed again when it comes round.
+    SetSpeed(f32),
+    /// Replaces the queue with this track and plays it.
+    LoadTrack(TrackRef),
+    /// Inserts the track after the current one and plays it. Tracks that do
+    /// not fit in the queue are dropped, here and below.
+    PlayNow(TrackRef),
+    /// Inserts the track after the current one.
+    PlayNext(TrackRef),
+    /// Adds the track to the end of the queue.
+    Enqueue(TrackRef),
+    /// Removes the track at this queue index. Removing the current track
+    /// moves on to the one after it.
+    Remove(usize),
+    /// Moves a track within the queue.
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// Empties the queue and stops.
+    ClearQueue,
+    /// Plays the track at this queue index.
+    JumpTo(usize),
+    /// A seed restarts the shuffle generator first, for an order that can be
+    /// reproduced.
+    SetShuffle {
+        mode: ShuffleMode,
+        seed: Option<u32>,
+    },
+    SetRepeat(RepeatMode),
+    /// Makes the track at this queue index current at `position_ms`, playing
+    /// or paused, what a saved session comes back as.
+    Restore {
+        index: usize,
+        position_ms: u64,
+        play: bool,
+    },
+}
+
+/// What `player_task` has to do to follow a transition.
+#[derive(Clone, Copy, Debug)]
+pub enum PlayerAction {
+    /// Open a decoder for the track, dropping the current one.
+    Load(TrackRef),
+    /// Open a decoder for the track and seek it to `position_ms`.
+    LoadAt {
+        track: TrackRef,
+        position_ms: u64,
+    },
+    /// Drop the current decoder.
+    Unload,
+    Seek {
+        position_ms: u64,
+    },
+    SetVolume(f32),
+    SetSpeed(f32),
+}
+
+#[derive(Default)]
+pub struct Player {
+    state: PlaybackState,
+    queue: Queue,
+    repeat: RepeatMode,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state
+    }
+
+    pub fn current_track(&self) -> Option<TrackRef> {
+        self.queue.current()
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    /// `position_ms` is how far into the current track playback is.
+    pub fn handle(&mut self, command: PlayerCommand, position_ms: u64) -> Option<PlayerAction> {
+        match command {
+            PlayerCommand::Play => self.play(),
+            PlayerCommand::Pause => {
+                if self.state == PlaybackState::Playing {
+                    self.state = PlaybackState::Paused;
+                }
+                None
+            }
+            PlayerCommand::Toggle => match self.state {
+                PlaybackState::Playing => self.handle(PlayerCommand::Pause, position_ms),
+                PlaybackState::Paused | PlaybackState::Stopped => self.play(),
+            },
+            PlayerCommand::Stop => self.stop(),
+            PlayerCommand::Next => match self.queue.next_index() {
+                Some(next) => self.select(next),
+                None if self.repeat == RepeatMode::All => self.restart_queue(),
+                None => None,
+            },
+            // Steps back through the play order, so shuffled history too.
+            PlayerCommand::Previous => match self.queue.previous_index() {
+                Some(previous) if position_ms <= PREVIOUS_RESTART_MS => self.select(previous),
+                _ => self.seek(0),
+            },
+            PlayerCommand::Seek { position_ms } => self.seek(position_ms),
+            PlayerCommand::SetVolume(volume_db) => Some(PlayerAction::SetVolume(volume_db)),
+            PlayerCommand::SetSpeed(speed) => {
+                if !self.queue.set_current_speed(speed) {
+                    return None;
+                }
+                match self.state {
+                    PlaybackState::Stopped => None,
+                    PlaybackState::Playing | PlaybackState::Paused => {
+                        Some(PlayerAction::SetSpeed(speed))
+                    }
+                }
+            }
+            PlayerCommand::LoadTrack(track) => {
+                self.queue.replace(track);
+                self.start()
+            }
+            PlayerCommand::PlayNow(track) => {
+                self.queue.play_now(track).ok()?;
+                self.start()
+            }
+            PlayerCommand::PlayNext(track) => {
+                let _ = self.queue.play_next(track);
+                None
+            }
+            PlayerCommand::Enqueue(track) => {
+                let _ = self.queue.append(track);
+                None
+            }
+            PlayerCommand::Remove(index) => {
+                let was_current = self.queue.current_index() == Some(index);
+                self.queue.remove(index)?;
+                if !was_current {
+                    return None;
+                }
+                match self.queue.current_index() {
+                    Some(current) => self.select(current),
+                    None => self.stop(),
+                }
+            }
+            PlayerCommand::Move { from, to } => {
+                self.queue.move_track(from, to);
+                None
+            }
+            PlayerCommand::ClearQueue => {
+                self.queue.clear();
+                self.stop()
+            }
+            PlayerCommand::JumpTo(index) => {
+                self.queue.jump(index)?;
+                self.start()
+            }
+            PlayerCommand::SetShuffle { mode, seed } => {
+                if let Some(seed) = seed {
+                    self.queue.set_seed(seed);
+                }
+                self.queue.set_shuffle(mode);
+                None
+            }
+            PlayerCommand::SetRepeat(repeat) => {
+                self.repeat = repeat;
+                None
+            }
+            PlayerCommand::Restore {
+                index,
+                position_ms,
+                play,
+            } => {
+                let track = self.queue.jump(index)?;
+                self.state = if play {
+                    PlaybackState::Playing
+                } else {
+                    PlaybackState::Paused
+                };
+                Some(PlayerAction::LoadAt { track, position_ms })
+            }
+        }
+    }
+
+    /// The current track played to the end, moves on to the next one or stops.
+    pub fn track_ended(&mut self) -> Option<PlayerAction> {
+        if self.repeat == RepeatMode::One
+            && let Some(current) = self.queue.current_index()
+        {
+            return self.select(current);
+        }
+        match self.queue.next_index() {
+            Some(next) => self.select(next),
+            None if self.repeat == RepeatMode::All => self.restart_queue(),
+            None => {
+                self.queue.rewind();
+                self.stop()
+            }
+        }
+    }
+
+    /// Goes round to the first track of a new round.
+    fn restart_queue(&mut self) -> Option<PlayerAction> {
+        self.queue.rewind();
+        let first = self.queue.next_index()?;
+        self.select(first)
+    }
+
+    /// Plays the current track from the top.
+    fn start(&mut self) -> Option<PlayerAction> {
+        let track = self.queue.current()?;
+        self.state = PlaybackState::Playing;
+        Some(PlayerAction::Load(track))
+    }
+
+    fn stop(&mut self) -> Option<PlayerAction> {
+        if self.state == PlaybackState::Stopped {
+            return None;
+        }
+        self.state = PlaybackState::Stopped;
+        Some(PlayerAction::Unload)
+    }
+
+    fn play(&mut self) -> Option<PlayerAction> {
+        match self.state {
+            PlaybackState::Playing => None,
+            PlaybackState::Paused => {
+                self.state = PlaybackState::Playing;
+                None
+            }
+            PlaybackState::Stopped => {
+                let index = self.queue.current_index().or(self.queue.next_index())?;
+                self.queue.jump(index)?;
+                self.start()
+            }
+        }
+    }
+
+    /// Moves to the track at `index`. Loads it unless stopped, in which case it
+    /// only becomes the track the next Play starts from.
+    fn select(&mut self, index: usize) -> Option<PlayerAction> {
+        let track = self.queue.jump(index)?;
+        match self.state {
+            PlaybackState::Stopped => None,
+            PlaybackState::Playing | PlaybackState::Paused => Some(PlayerAction::Load(track)),
+        }
+    }
+
+    fn seek(&mut self, position_ms: u64) -> Option<PlayerAction> {
+        match self.state {
+            PlaybackState::Stopped => None,
+            PlaybackState::Playing | PlaybackState::Paused => {
+                Some(PlayerAction::Seek { position_ms })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(file_name: &'static str) -> TrackRef {
+        TrackRef::new(FileInfo {
+            file_name,
+            file_bytes: &[],
+        })
+    }
+
+    fn loaded(action: Option<PlayerAction>) -> Option<&'static str> {
+        match action {
+            Some(PlayerAction::Load(track)) => Some(track.path()),
+            _ => None,
+        }
+    }
+
+    fn player(paths: &[&'static str]) -> Player {
+        let mut player = Player::new();
+        for &path in paths {
+            player.handle(PlayerCommand::Enqueue(track(path)), 0);
+        }
+        player
+    }
+
+    #[test]
+    fn play_starts_the_first_track_and_toggle_pauses_and_resumes() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        assert_eq!(player.state(), PlaybackState::Stopped);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Play, 0)),
+            Some("/a.flac")
+        );
+        assert_eq!(player.state(), PlaybackState::Playing);
+        assert!(player.handle(PlayerCommand::Play, 0).is_none());
+        assert!(player.handle(PlayerCommand::Toggle, 0).is_none());
+        assert_eq!(player.state(), PlaybackState::Paused);
+        assert!(player.handle(PlayerCommand::Toggle, 0).is_none());
+        assert_eq!(player.state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn an_empty_queue_does_not_play() {
+        let mut player = Player::new();
+        assert!(player.handle(PlayerCommand::Play, 0).is_none());
+        assert_eq!(player.state(), PlaybackState::Stopped);
+    }
+
+    #[test]
+    fn stop_unloads_once() {
+        let mut player = player(&["/a.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        assert!(matches!(
+            player.handle(PlayerCommand::Stop, 0),
+            Some(PlayerAction::Unload)
+        ));
+        assert!(player.handle(PlayerCommand::Stop, 0).is_none());
+        assert_eq!(player.current_track().map(|t| t.path()), Some("/a.flac"));
+    }
+
+    #[test]
+    fn next_and_previous_move_through_the_queue() {
+        let mut player = player(&["/a.flac", "/b.flac", "/c.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Next, 0)),
+            Some("/b.flac")
+        );
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Next, 0)),
+            Some("/c.flac")
+        );
+        assert!(player.handle(PlayerCommand::Next, 0).is_none());
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Previous, PREVIOUS_RESTART_MS)),
+            Some("/b.flac")
+        );
+    }
+
+    #[test]
+    fn previous_restarts_a_track_that_is_well_under_way() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        player.handle(PlayerCommand::Next, 0);
+        assert!(matches!(
+            player.handle(PlayerCommand::Previous, PREVIOUS_RESTART_MS + 1),
+            Some(PlayerAction::Seek { position_ms: 0 })
+        ));
+        assert_eq!(player.current_track().map(|t| t.path()), Some("/b.flac"));
+    }
+
+    #[test]
+    fn moving_while_stopped_only_selects() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        assert!(player.handle(PlayerCommand::JumpTo(1), 0).is_some());
+        player.handle(PlayerCommand::Stop, 0);
+        assert!(player.handle(PlayerCommand::Previous, 0).is_none());
+        assert_eq!(player.current_track().map(|t| t.path()), Some("/a.flac"));
+        assert!(
+            player
+                .handle(PlayerCommand::Seek { position_ms: 5 }, 0)
+                .is_none()
+        );
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Play, 0)),
+            Some("/a.flac")
+        );
+    }
+
+    #[test]
+    fn the_end_of_the_queue_stops_and_rewinds() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        assert_eq!(loaded(player.track_ended()), Some("/b.flac"));
+        assert!(matches!(player.track_ended(), Some(PlayerAction::Unload)));
+        assert_eq!(player.state(), PlaybackState::Stopped);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Play, 0)),
+            Some("/a.flac")
+        );
+    }
+
+    #[test]
+    fn repeat_one_replays_and_repeat_all_goes_round() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        player.handle(PlayerCommand::SetRepeat(RepeatMode::One), 0);
+        assert_eq!(loaded(player.track_ended()), Some("/a.flac"));
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Next, 0)),
+            Some("/b.flac")
+        );
+        player.handle(PlayerCommand::SetRepeat(RepeatMode::All), 0);
+        assert_eq!(loaded(player.track_ended()), Some("/a.flac"));
+        assert_eq!(player.state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn removing_the_current_track_loads_the_next_or_stops() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::Remove(0), 0)),
+            Some("/b.flac")
+        );
+        assert!(
+            player
+                .handle(PlayerCommand::Enqueue(track("/c.flac")), 0)
+                .is_none()
+        );
+        assert!(player.handle(PlayerCommand::Remove(1), 0).is_none());
+        assert!(matches!(
+            player.handle(PlayerCommand::Remove(0), 0),
+            Some(PlayerAction::Unload)
+        ));
+        assert!(player.queue().is_empty());
+    }
+
+    #[test]
+    fn load_track_and_play_now_start_playing() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::PlayNow(track("/n.flac")), 0)),
+            Some("/n.flac")
+        );
+        assert_eq!(player.queue().len(), 3);
+        assert_eq!(
+            loaded(player.handle(PlayerCommand::LoadTrack(track("/l.flac")), 0)),
+            Some("/l.flac")
+        );
+        assert_eq!(player.queue().len(), 1);
+        assert_eq!(player.state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn clear_queue_stops() {
+        let mut player = player(&["/a.flac"]);
+        player.handle(PlayerCommand::Play, 0);
+        assert!(matches!(
+            player.handle(PlayerCommand::ClearQueue, 0),
+            Some(PlayerAction::Unload)
+        ));
+        assert!(player.current_track().is_none());
+    }
+
+    #[test]
+    fn restore_loads_at_the_position_playing_or_paused() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        let restore = PlayerCommand::Restore {
+            index: 1,
+            position_ms: 42_000,
+            play: false,
+        };
+        assert!(matches!(
+            player.handle(restore, 0),
+            Some(PlayerAction::LoadAt { track, position_ms: 42_000 }) if track.path() == "/b.flac"
+        ));
+        assert_eq!(player.state(), PlaybackState::Paused);
+        let out_of_range = PlayerCommand::Restore {
+            index: 2,
+            position_ms: 0,
+            play: true,
+        };
+        assert!(player.handle(out_of_range, 0).is_none());
+    }
+
+    #[test]
+    fn speed_stays_with_the_track() {
+        let mut player = player(&["/a.flac", "/b.flac"]);
+        assert!(player.handle(PlayerCommand::SetSpeed(1.5), 0).is_none());
+        player.handle(PlayerCommand::Play, 0);
+        assert!(matches!(
+            player.handle(PlayerCommand::SetSpeed(1.5), 0),
+            Some(PlayerAction::SetSpeed(1.5))
+        ));
+        let speed = |action| match action {
+            Some(PlayerAction::Load(track)) => track.speed,
+            _ => panic!("expected a load"),
+        };
+        assert_eq!(speed(player.handle(PlayerCommand::Next, 0)), None);
+        assert_eq!(speed(player.handle(PlayerCommand::Previous, 0)), Some(1.5));
+    }
+}
@@ -0,0 +1,578 @@
+//! What plays next.
+//!
+//! [`Queue`] holds [`TrackRef`]s, the order they play in and a cursor on the
+//! current one. Every edit keeps the cursor on the same track, so the queue
+//! can be rearranged while that track plays without interrupting it, and
+//! shuffling walks a fixed order so going back retraces what played.
+
+use heapless::Vec;
+
+pub const QUEUE_CAPACITY: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FileInfo {
+    pub file_name: &'static str,
+    pub file_bytes: &'static [u8],
+}
+
+/// A track in the queue, a whole file or one track of the CUE sheet next to it.
+/// Two are equal when they name the same file and CUE track and play it the
+/// same way.
+#[derive(Clone, Copy, Debug)]
+pub struct TrackRef {
+    pub file: FileInfo,
+    /// 1-based track number in the file's CUE sheet.
+    pub cue_track: Option<u8>,
+    /// Playback speed chosen for this track, it plays at 1.0 when unset.
+    pub speed: Option<f32>,
+}
+
+impl TrackRef {
+    pub fn new(file: FileInfo) -> Self {
+        Self {
+            file,
+            cue_track: None,
+            speed: None,
+        }
+    }
+
+    pub fn cue(file: FileInfo, cue_track: u8) -> Self {
+        Self {
+            cue_track: Some(cue_track),
+            ..Self::new(file)
+        }
+    }
+
+    pub fn with_speed(self, speed: Option<f32>) -> Self {
+        Self { speed, ..self }
+    }
+
+    pub fn path(&self) -> &'static str {
+        self.file.file_name
+    }
+}
+
+impl PartialEq for TrackRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.path() == other.path()
+            && self.cue_track == other.cue_track
+            && self.speed == other.speed
+    }
+}
+
+impl From<FileInfo> for TrackRef {
+    fn from(file: FileInfo) -> Self {
+        Self::new(file)
+    }
+}
+
+/// How [`Queue`] orders playback.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShuffleMode {
+    /// Queue order.
+    #[default]
+    Off,
+    Tracks,
+    /// Albums in random order, the tracks of each in queue order. An album is
+    /// a run of tracks from the same directory, or CUE tracks of one file.
+    Albums,
+}
+
+/// xorshift32, plenty for shuffling and the same order for the same seed.
+#[derive(Clone, Copy, Debug)]
+struct ShuffleRng(u32);
+
+impl ShuffleRng {
+    const DEFAULT_SEED: u32 = 0x2545_f491;
+
+    fn new(seed: u32) -> Self {
+        // Zero is the one state xorshift never leaves.
+        Self(if seed == 0 { Self::DEFAULT_SEED } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// Uniform in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        ((self.next() as u64 * n as u64) >> 32) as usize
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+impl Default for ShuffleRng {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SEED)
+    }
+}
+
+#[derive(Default)]
+pub struct Queue {
+    tracks: Vec<TrackRef, QUEUE_CAPACITY>,
+    /// Indices into `tracks` in the order they play, in queue order unless
+    /// shuffling. Everything before `position` has played this round.
+    order: Vec<usize, QUEUE_CAPACITY>,
+    /// Where in `order` the loaded, or when stopped the selected, track is.
+    position: Option<usize>,
+    shuffle: ShuffleMode,
+    rng: ShuffleRng,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tracks(&self) -> &[TrackRef] {
+        &self.tracks
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<TrackRef> {
+        self.tracks.get(index).copied()
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.position.map(|position| self.order[position])
+    }
+
+    pub fn current(&self) -> Option<TrackRef> {
+        self.current_index().map(|index| self.tracks[index])
+    }
+
+    /// Index of the track that plays after the current one, the first to play
+    /// if there is no current one.
+    pub fn next_index(&self) -> Option<usize> {
+        let next = self.position.map_or(0, |position| position + 1);
+        self.order.get(next).copied()
+    }
+
+    /// Index of the track that played before the current one.
+    pub fn previous_index(&self) -> Option<usize> {
+        let previous = self.position?.checked_sub(1)?;
+        self.order.get(previous).copied()
+    }
+
+    /// Sets the playback speed of the current track, returns false if there
+    /// is none.
+    pub fn set_current_speed(&mut self, speed: f32) -> bool {
+        let Some(index) = self.current_index() else {
+            return false;
+        };
+        self.tracks[index].speed = Some(speed);
+        true
+    }
+
+    /// Makes the track at `index` current, returns it.
+    pub fn jump(&mut self, index: usize) -> Option<TrackRef> {
+        let track = self.get(index)?;
+        self.position = self.order.iter().position(|&i| i == index);
+        Some(track)
+    }
+
+    /// Drops the cursor and starts a new round, reshuffled when shuffling.
+    pub fn rewind(&mut self) {
+        self.position = None;
+        self.reorder();
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    /// Reorders everything but the current track, which stays current and
+    /// counts as the first to play.
+    pub fn set_shuffle(&mut self, shuffle: ShuffleMode) {
+        self.shuffle = shuffle;
+        self.reorder();
+    }
+
+    /// Restarts the shuffle generator, the same seed on the same queue gives
+    /// the same order.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng = ShuffleRng::new(seed);
+    }
+
+    /// Adds the track at the end of the queue. When shuffling it goes
+    /// somewhere among the tracks still to play, with its album if that is
+    /// still to play. Hands it back if the queue is full.
+    pub fn append(&mut self, track: TrackRef) -> Result<(), TrackRef> {
+        let index = self.tracks.len();
+        self.tracks.push(track)?;
+        let unplayed = self.position.map_or(0, |position| position + 1);
+        let slot = match self.shuffle {
+            ShuffleMode::Off => self.order.len(),
+            ShuffleMode::Tracks => unplayed + self.rng.below(self.order.len() - unplayed + 1),
+            ShuffleMode::Albums => self.order[unplayed..]
+                .iter()
+                .rposition(|&i| self.album(i) == self.album(index))
+                .map_or(self.order.len(), |offset| unplayed + offset + 1),
+        };
+        let _ = self.order.insert(slot, index);
+        Ok(())
+    }
+
+    /// Inserts the track right after the current one, in the queue and in
+    /// the play order. Hands it back if the queue is full.
+    pub fn play_next(&mut self, track: TrackRef) -> Result<usize, TrackRef> {
+        let index = self.current_index().map_or(0, |current| current + 1);
+        self.tracks.insert(index, track)?;
+        for i in self.order.iter_mut().filter(|i| **i >= index) {
+            *i += 1;
+        }
+        let slot = self.position.map_or(0, |position| position + 1);
+        let _ = self.order.insert(slot, index);
+        Ok(index)
+    }
+
+    /// Inserts the track right after the current one and makes it current.
+    pub fn play_now(&mut self, track: TrackRef) -> Result<(), TrackRef> {
+        self.play_next(track)?;
+        self.position = Some(self.position.map_or(0, |position| position + 1));
+        Ok(())
+    }
+
+    /// Replaces the whole queue with one track and makes it current.
+    pub fn replace(&mut self, track: TrackRef) {
+        self.clear();
+        let _ = self.tracks.push(track);
+        let _ = self.order.push(0);
+        self.position = Some(0);
+    }
+
+    /// Removes the track at `index`. When that was the current track the
+    /// cursor is left on whatever plays after it, if anything does.
+    pub fn remove(&mut self, index: usize) -> Option<TrackRef> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+        let track = self.tracks.remove(index);
+        let removed = self.order.iter().position(|&i| i == index)?;
+        self.order.remove(removed);
+        for i in self.order.iter_mut().filter(|i| **i > index) {
+            *i -= 1;
+        }
+        self.position = match self.position {
+            Some(position) if position > removed => Some(position - 1),
+            Some(position) if position == removed && position == self.order.len() => None,
+            position => position,
+        };
+        Some(track)
+    }
+
+    /// Moves the track at `from` so it ends up at `to`, the play order only
+    /// changes when not shuffling. Returns false if either is out of range.
+    pub fn move_track(&mut self, from: usize, to: usize) -> bool {
+        let len = self.tracks.len();
+        if from >= len || to >= len {
+            return false;
+        }
+        if from < to {
+            self.tracks[from..=to].rotate_left(1);
+        } else {
+            self.tracks[to..=from].rotate_right(1);
+        }
+        let moved = |index: usize| match index {
+            index if index == from => to,
+            index if from < index && index <= to => index - 1,
+            index if to <= index && index < from => index + 1,
+            index => index,
+        };
+        for i in self.order.iter_mut() {
+            *i = moved(*i);
+        }
+        if self.shuffle == ShuffleMode::Off {
+            // Queue order again, where the position is the queue index.
+            self.order.sort_unstable();
+            self.position = self.position.map(moved);
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.order.clear();
+        self.position = None;
+    }
+
+    /// What groups tracks for [`ShuffleMode::Albums`].
+    fn album(&self, index: usize) -> &str {
+        let track = &self.tracks[index];
+        match track.cue_track {
+            Some(_) => track.path(),
+            None => track.path().rsplit_once('/').map_or("", |(dir, _)| dir),
+        }
+    }
+
+    /// Builds the play order for the shuffle mode afresh. The album, or the
+    /// track, of the current track goes first with the cursor left on it.
+    fn reorder(&mut self) {
+        let current = self.current_index();
+        self.order.clear();
+        self.order.extend(0..self.tracks.len());
+        match self.shuffle {
+            ShuffleMode::Off => {
+                self.position = current;
+                return;
+            }
+            ShuffleMode::Tracks => self.rng.shuffle(&mut self.order),
+            ShuffleMode::Albums => {
+                // Album runs in queue order as (first, end) index pairs.
+                let mut albums: Vec<(usize, usize), QUEUE_CAPACITY> = Vec::new();
+                for index in 0..self.tracks.len() {
+                    match albums.last_mut() {
+                        Some((first, end)) if self.album(*first) == self.album(index) => {
+                            *end = index + 1
+                        }
+                        _ => {
+                            let _ = albums.push((index, index + 1));
+                        }
+                    }
+                }
+                self.rng.shuffle(&mut albums);
+                self.order.clear();
+                for &(first, end) in &albums {
+                    self.order.extend(first..end);
+                }
+            }
+        }
+        self.position = current.map(|current| {
+            let position = self.order.iter().position(|&i| i == current).unwrap_or(0);
+            let (start, end) = match self.shuffle {
+                ShuffleMode::Albums => {
+                    let album = self.album(current);
+                    let start = self.order[..position]
+                        .iter()
+                        .rposition(|&i| self.album(i) != album)
+                        .map_or(0, |before| before + 1);
+                    let end = self.order[position..]
+                        .iter()
+                        .position(|&i| self.album(i) != album)
+                        .map_or(self.order.len(), |after| position + after);
+                    (start, end)
+                }
+                ShuffleMode::Off | ShuffleMode::Tracks => (position, position + 1),
+            };
+            self.order[..end].rotate_right(end - start);
+            position - start
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATHS: [&str; 6] = [
+        "/x/1.flac",
+        "/x/2.flac",
+        "/y/1.flac",
+        "/y/2.flac",
+        "/y/3.flac",
+        "/z/1.flac",
+    ];
+
+    fn track(file_name: &'static str) -> TrackRef {
+        TrackRef::new(FileInfo {
+            file_name,
+            file_bytes: &[],
+        })
+    }
+
+    fn queue(paths: &[&'static str]) -> Queue {
+        let mut queue = Queue::new();
+        for &path in paths {
+            queue.append(track(path)).unwrap();
+        }
+        queue
+    }
+
+    fn paths(queue: &Queue) -> alloc::vec::Vec<&'static str> {
+        queue.tracks().iter().map(|track| track.path()).collect()
+    }
+
+    fn current(queue: &Queue) -> Option<&'static str> {
+        queue.current().map(|track| track.path())
+    }
+
+    #[test]
+    fn a_full_queue_hands_the_track_back() {
+        let mut queue = Queue::new();
+        for _ in 0..QUEUE_CAPACITY {
+            queue.append(track("/a.flac")).unwrap();
+        }
+        assert!(queue.append(track("/b.flac")).is_err());
+        assert!(queue.play_next(track("/b.flac")).is_err());
+        assert_eq!(queue.len(), QUEUE_CAPACITY);
+    }
+
+    #[test]
+    fn play_next_goes_right_after_the_current_track() {
+        let mut queue = queue(&PATHS[..3]);
+        queue.jump(1);
+        assert_eq!(queue.play_next(track("/n.flac")), Ok(2));
+        assert_eq!(paths(&queue)[2], "/n.flac");
+        assert_eq!(current(&queue), Some(PATHS[1]));
+        assert_eq!(queue.next_index(), Some(2));
+        queue.play_now(track("/m.flac")).unwrap();
+        assert_eq!(current(&queue), Some("/m.flac"));
+        assert_eq!(
+            queue.next_index().and_then(|i| queue.get(i)),
+            Some(track("/n.flac"))
+        );
+    }
+
+    #[test]
+    fn removing_keeps_the_cursor_on_the_current_track() {
+        let mut queue = queue(&PATHS[..4]);
+        queue.jump(2);
+        assert_eq!(queue.remove(0), Some(track(PATHS[0])));
+        assert_eq!(current(&queue), Some(PATHS[2]));
+        assert_eq!(queue.remove(3), None);
+        assert_eq!(queue.remove(1), Some(track(PATHS[2])));
+        assert_eq!(current(&queue), Some(PATHS[3]));
+        queue.remove(1);
+        assert_eq!(current(&queue), None);
+        assert_eq!(paths(&queue), [PATHS[1]]);
+    }
+
+    #[test]
+    fn moving_keeps_the_cursor_on_the_current_track() {
+        let mut queue = queue(&PATHS[..4]);
+        queue.jump(1);
+        assert!(queue.move_track(1, 3));
+        assert_eq!(paths(&queue), [PATHS[0], PATHS[2], PATHS[3], PATHS[1]]);
+        assert_eq!(current(&queue), Some(PATHS[1]));
+        assert_eq!(queue.next_index(), None);
+        assert!(queue.move_track(0, 3));
+        assert_eq!(current(&queue), Some(PATHS[1]));
+        assert_eq!(
+            queue.next_index().and_then(|i| queue.get(i)),
+            Some(track(PATHS[0]))
+        );
+        assert!(!queue.move_track(0, 4));
+    }
+
+    /// Track paths in play order from the current one, to the end of the round.
+    fn round(queue: &mut Queue) -> alloc::vec::Vec<&'static str> {
+        let mut played = alloc::vec::Vec::new();
+        while let Some(index) = queue.next_index() {
+            played.push(queue.jump(index).unwrap().path());
+        }
+        played
+    }
+
+    fn sorted(mut paths: alloc::vec::Vec<&'static str>) -> alloc::vec::Vec<&'static str> {
+        paths.sort_unstable();
+        paths
+    }
+
+    #[test]
+    fn a_shuffled_round_plays_every_track_once() {
+        let mut queue = queue(&PATHS);
+        queue.set_seed(7);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        let first = round(&mut queue);
+        assert_eq!(sorted(first.clone()), PATHS);
+        queue.rewind();
+        let second = round(&mut queue);
+        assert_eq!(sorted(second.clone()), PATHS);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn shuffling_keeps_the_current_track_current() {
+        let mut queue = queue(&PATHS);
+        queue.jump(3);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        assert_eq!(current(&queue), Some(PATHS[3]));
+        assert_eq!(queue.previous_index(), None);
+        let mut played = round(&mut queue);
+        played.push(PATHS[3]);
+        assert_eq!(sorted(played), PATHS);
+    }
+
+    #[test]
+    fn previous_retraces_the_shuffled_order() {
+        let mut queue = queue(&PATHS);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        let played = round(&mut queue);
+        for &path in played.iter().rev().skip(1) {
+            let previous = queue.previous_index().unwrap();
+            assert_eq!(queue.jump(previous).map(|track| track.path()), Some(path));
+        }
+        assert_eq!(queue.previous_index(), None);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_order() {
+        let shuffled = |seed| {
+            let mut queue = queue(&PATHS);
+            queue.set_seed(seed);
+            queue.set_shuffle(ShuffleMode::Tracks);
+            round(&mut queue)
+        };
+        assert_eq!(shuffled(1), shuffled(1));
+        assert_ne!(shuffled(1), shuffled(2));
+    }
+
+    #[test]
+    fn appending_while_shuffled_lands_among_the_unplayed() {
+        let mut queue = queue(&PATHS[..4]);
+        queue.set_shuffle(ShuffleMode::Tracks);
+        let first = queue.next_index().unwrap();
+        queue.jump(first);
+        queue.append(track(PATHS[4])).unwrap();
+        queue.append(track(PATHS[5])).unwrap();
+        let mut played = round(&mut queue);
+        played.push(queue.get(first).unwrap().path());
+        assert_eq!(sorted(played), PATHS);
+    }
+
+    #[test]
+    fn album_shuffle_keeps_albums_together_in_queue_order() {
+        let mut queue = queue(&PATHS);
+        queue.set_seed(3);
+        queue.set_shuffle(ShuffleMode::Albums);
+        let played = round(&mut queue);
+        assert_eq!(sorted(played.clone()), PATHS);
+        let y = played
+            .iter()
+            .position(|path| path.starts_with("/y/"))
+            .unwrap();
+        assert_eq!(played[y..y + 3], PATHS[2..5]);
+        let x = played
+            .iter()
+            .position(|path| path.starts_with("/x/"))
+            .unwrap();
+        assert_eq!(played[x..x + 2], PATHS[..2]);
+    }
+
+    #[test]
+    fn cue_tracks_of_one_file_are_an_album_of_their_own() {
+        let image = track("/x/image.flac").file;
+        let mut queue = queue(&["/x/1.flac"]);
+        for cue_track in 1..=3 {
+            queue.append(TrackRef::cue(image, cue_track)).unwrap();
+        }
+        assert_ne!(queue.get(1), queue.get(2));
+        assert_ne!(queue.album(0), queue.album(1));
+        assert_eq!(queue.album(1), queue.album(3));
+    }
+}
@@ -0,0 +1,424 @@
+//! M3U and M3U8 playlists on the SD card.
+//!
+//! [`parse`] turns a playlist into [`PlaylistEntry`]s whose paths start from
+//! the card root, whether the file used relative or absolute ones. [`load`]
+//! reads one off the card, [`enqueue`] hands the entries to the player and
+//! [`save`] writes a queue back out, which [`QueueWriter`] does whenever the
+//! queue has settled after a change. Entries that cannot be opened are logged
+//! and skipped, one bad line never loses the rest of the playlist.
+//!
+//! Playing the audio files on the card is out of scope for now: the decoder
+//! only reads whole files out of memory and nothing streams from the card
+//! yet. Until something does, the `open` that `sdcard_task` passes to
+//! [`enqueue`] only finds tracks built into the firmware and every entry for
+//! a file on the card is reported as missing.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(target_os = "none")]
+use core::fmt::Write;
+
+use defmt::info;
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(target_os = "none")]
+use embedded_sdmmc::Mode as FileMode;
+
+#[cfg(target_os = "none")]
+use crate::DirectoryType;
+use crate::audio::player::{PlayerCommand, QUEUE_CAPACITY, TrackRef};
+use crate::audio::{FileInfo, PLAYER_COMMANDS, PLAYER_QUEUE, QueueReceiver};
+
+pub const PATH_CAPACITY: usize = 128;
+pub const TITLE_CAPACITY: usize = 64;
+/// Largest playlist [`load`] reads, anything past it is ignored.
+pub const MAX_PLAYLIST_BYTES: usize = 16 * 1024;
+/// How long after the queue changes [`QueueWriter`] writes it, a burst of
+/// edits is one write.
+pub const QUEUE_SETTLE_MS: u64 = 3_000;
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaylistEntry {
+    /// From the card root, `/` separated, without a leading `/`.
+    pub path: heapless::String<PATH_CAPACITY>,
+    /// From `#EXTINF`, empty if there was none.
+    pub title: heapless::String<TITLE_CAPACITY>,
+    /// From `#EXTINF`, `None` if it was missing or -1.
+    pub duration_s: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EnqueueReport {
+    pub queued: usize,
+    /// Entries `open` could not find.
+    pub missing: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// What players wrote plain `.m3u` files in before M3U8.
+    Windows1252,
+}
+
+impl Encoding {
+    /// `.m3u8` files and files with a BOM are UTF-8, other `.m3u` files only
+    /// when they decode as UTF-8.
+    pub fn detect(file_name: &str, bytes: &[u8]) -> Self {
+        let is_m3u8 = file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("m3u8"));
+        if is_m3u8 || bytes.starts_with(UTF8_BOM) || core::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Windows1252
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => {
+                String::from_utf8_lossy(bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes)).into()
+            }
+            Encoding::Windows1252 => bytes.iter().map(|&byte| windows_1252(byte)).collect(),
+        }
+    }
+}
+
+/// Windows-1252 is Latin-1 apart from the printable characters it puts in
+/// 0x80-0x9F.
+fn windows_1252(byte: u8) -> char {
+    const HIGH_CONTROL: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž',
+        '\u{8f}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}',
+        'ž', 'Ÿ',
+    ];
+    match byte {
+        0x80..=0x9f => HIGH_CONTROL[(byte - 0x80) as usize],
+        byte => byte as char,
+    }
+}
+
+/// Parses a playlist. `base_dir` is the directory the playlist is in, from
+/// the card root, and is what relative entries are resolved against.
+pub fn parse(text: &str, base_dir: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut extinf: Option<(Option<u32>, &str)> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(line_info) = line.strip_prefix("#EXTINF:") {
+            extinf = Some(parse_extinf(line_info));
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            let (duration_s, title) = extinf.take().unwrap_or((None, ""));
+            let Some(path) = resolve_path(base_dir, line) else {
+                info!("Playlist entry does not fit a path: {}", line);
+                continue;
+            };
+            let mut entry = PlaylistEntry {
+                path,
+                duration_s,
+                ..PlaylistEntry::default()
+            };
+            for c in title.chars() {
+                if entry.title.push(c).is_err() {
+                    break;
+                }
+            }
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// `<duration> [attributes],<title>`
+fn parse_extinf(extinf: &str) -> (Option<u32>, &str) {
+    let (head, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+    let duration_s = head
+        .split_whitespace()
+        .next()
+        .and_then(|duration| duration.parse::<f32>().ok())
+        .filter(|&duration| duration >= 0.0)
+        .map(|duration| duration as u32);
+    (duration_s, title.trim())
+}
+
+/// Joins `path` onto `base_dir` unless it is absolute and folds away `.` and
+/// `..`. Takes `\` as a separator and drops drive letters, playlists written
+/// on Windows are common.
+fn resolve_path(base_dir: &str, path: &str) -> Option<heapless::String<PATH_CAPACITY>> {
+    let path = path.strip_prefix("file://").unwrap_or(path);
+    let (path, absolute) = match path.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => (&path[2..], true),
+        [b'/' | b'\\', ..] => (path, true),
+        _ => (path, false),
+    };
+    let base = if absolute { "" } else { base_dir };
+    let mut components: heapless::Vec<&str, 32> = heapless::Vec::new();
+    for component in base.split(['/', '\\']).chain(path.split(['/', '\\'])) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component).ok()?,
+        }
+    }
+    let mut resolved = heapless::String::new();
+    for (i, component) in components.iter().enumerate() {
+        if i > 0 {
+            resolved.push('/').ok()?;
+        }
+        resolved.push_str(component).ok()?;
+    }
+    Some(resolved)
+}
+
+/// Reads and parses the playlist `file_name` in `dir`, which is `dir_path`
+/// from the card root.
+#[cfg(target_os = "none")]
+pub fn load(dir: &DirectoryType, dir_path: &str, file_name: &str) -> Option<Vec<PlaylistEntry>> {
+    let file = match dir.open_file_in_dir(file_name, FileMode::ReadOnly) {
+        Ok(file) => file,
+        Err(e) => {
+            info!(
+                "Error opening playlist {}: {}",
+                file_name,
+                defmt::Debug2Format(&e)
+            );
+            return None;
+        }
+    };
+    let mut bytes = alloc::vec![0; MAX_PLAYLIST_BYTES];
+    let mut len = 0;
+    while !file.is_eof() && len < bytes.len() {
+        match file.read(&mut bytes[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) => {
+                info!(
+                    "Error reading playlist {}: {}",
+                    file_name,
+                    defmt::Debug2Format(&e)
+                );
+                return None;
+            }
+        }
+    }
+    if !file.is_eof() {
+        info!(
+            "Playlist {} is over {} bytes, reading only the start",
+            file_name, MAX_PLAYLIST_BYTES
+        );
+    }
+    let bytes = &bytes[..len];
+    let text = Encoding::detect(file_name, bytes).decode(bytes);
+    let entries = parse(&text, dir_path);
+    info!("Loaded {} entries from {}", entries.len(), file_name);
+    Some(entries)
+}
+
+/// Appends every entry `open` finds to the player queue, in order. Entries it
+/// does not find are logged as missing, see the module docs for what it can
+/// find.
+pub async fn enqueue(
+    entries: &[PlaylistEntry],
+    mut open: impl FnMut(&str) -> Option<FileInfo>,
+) -> EnqueueReport {
+    let mut report = EnqueueReport::default();
+    for entry in entries {
+        match open(&entry.path) {
+            Some(file) => {
+                PLAYER_COMMANDS
+                    .send(PlayerCommand::Enqueue(TrackRef::new(file)))
+                    .await;
+                report.queued += 1;
+            }
+            None => {
+                info!("Playlist entry missing: {}", entry.path.as_str());
+                report.missing += 1;
+            }
+        }
+    }
+    report
+}
+
+/// Writes `tracks` to `file_name` in `dir` as an M3U8 playlist, UTF-8 with
+/// absolute paths. The card only takes 8.3 names, so the name itself may
+/// have to end in `.M3U`.
+#[cfg(target_os = "none")]
+pub fn save(dir: &DirectoryType, file_name: &str, tracks: &[TrackRef]) {
+    let file = match dir.open_file_in_dir(file_name, FileMode::ReadWriteCreateOrTruncate) {
+        Ok(file) => file,
+        Err(e) => {
+            info!(
+                "Error creating playlist {}: {}",
+                file_name,
+                defmt::Debug2Format(&e)
+            );
+            return;
+        }
+    };
+    let mut text = String::from("#EXTM3U\n");
+    for track in tracks {
+        let _ = writeln!(text, "/{}", track.path().trim_start_matches('/'));
+    }
+    if let Err(e) = file.write(text.as_bytes()).and_then(|_| file.close()) {
+        info!(
+            "Error writing playlist {}: {}",
+            file_name,
+            defmt::Debug2Format(&e)
+        );
+    }
+}
+
+/// Follows [`PLAYER_QUEUE`] and decides when it is worth writing to the card.
+pub struct QueueWriter {
+    queue: QueueReceiver,
+    tracks: heapless::Vec<TrackRef, QUEUE_CAPACITY>,
+    /// First change since the last write.
+    changed_at: Option<Instant>,
+}
+
+impl QueueWriter {
+    /// `None` if every [`PLAYER_QUEUE`] receiver is taken.
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            queue: PLAYER_QUEUE.receiver()?,
+            tracks: heapless::Vec::new(),
+            changed_at: None,
+        })
+    }
+
+    /// Waits until the queue has changed and [`QUEUE_SETTLE_MS`] have passed
+    /// since. Dropping it early loses nothing.
+    pub async fn wait_due(&mut self) {
+        loop {
+            match self.changed_at {
+                Some(changed_at) => {
+                    let deadline = changed_at + Duration::from_millis(QUEUE_SETTLE_MS);
+                    match select(Timer::at(deadline), self.queue.changed()).await {
+                        Either::First(()) => return,
+                        Either::Second(tracks) => self.tracks = tracks,
+                    }
+                }
+                None => {
+                    self.tracks = self.queue.changed().await;
+                    self.changed_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Writes the latest queue to `file_name` in `dir`, see [`save`].
+    #[cfg(target_os = "none")]
+    pub fn save(&mut self, dir: &DirectoryType, file_name: &str) {
+        self.changed_at = None;
+        save(dir, file_name, &self.tracks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(base_dir: &str, path: &str) -> Option<String> {
+        resolve_path(base_dir, path).map(|path| path.as_str().into())
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_playlist_directory() {
+        assert_eq!(
+            resolved("music/lists", "a.flac").as_deref(),
+            Some("music/lists/a.flac")
+        );
+        assert_eq!(
+            resolved("music/lists", "./b/a.flac").as_deref(),
+            Some("music/lists/b/a.flac")
+        );
+        assert_eq!(
+            resolved("music/lists", "../a.flac").as_deref(),
+            Some("music/a.flac")
+        );
+        assert_eq!(resolved("", "../../a.flac").as_deref(), Some("a.flac"));
+    }
+
+    #[test]
+    fn absolute_paths_start_from_the_card_root() {
+        assert_eq!(resolved("music", "/x/a.flac").as_deref(), Some("x/a.flac"));
+        assert_eq!(
+            resolved("music", "file:///x/a.flac").as_deref(),
+            Some("x/a.flac")
+        );
+        assert_eq!(
+            resolved("music", "D:\\x\\a.flac").as_deref(),
+            Some("x/a.flac")
+        );
+        assert_eq!(
+            resolved("music", "\\x\\..\\a.flac").as_deref(),
+            Some("a.flac")
+        );
+    }
+
+    #[test]
+    fn a_path_over_capacity_does_not_resolve() {
+        let long = "a".repeat(PATH_CAPACITY + 1);
+        assert_eq!(resolved("", &long), None);
+        assert_eq!(
+            resolved("", &"a".repeat(PATH_CAPACITY)).map(|p| p.len()),
+            Some(PATH_CAPACITY)
+        );
+    }
+
+    #[test]
+    fn parse_reads_entries_and_their_extinf() {
+        let text = "#EXTM3U\r\n\
+                    #EXTINF:215 tvg-id=\"x\",Artist - Title\r\n\
+                    one.flac\r\n\
+                    \r\n\
+                    # a comment\r\n\
+                    #EXTINF:-1,\r\n\
+                    /two.flac\r\n\
+                    three.flac\r\n";
+        let entries = parse(text, "list");
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["list/one.flac", "two.flac", "list/three.flac"]);
+        assert_eq!(entries[0].duration_s, Some(215));
+        assert_eq!(entries[0].title.as_str(), "Artist - Title");
+        assert_eq!(entries[1].duration_s, None);
+        assert_eq!(entries[1].title.as_str(), "");
+        assert_eq!(
+            entries[2],
+            PlaylistEntry {
+                path: heapless::String::try_from("list/three.flac").unwrap(),
+                ..PlaylistEntry::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_skips_entries_that_do_not_fit() {
+        let text = alloc::format!("{}\nok.flac\n", "a".repeat(PATH_CAPACITY + 1));
+        let entries = parse(&text, "");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path.as_str(), "ok.flac");
+    }
+
+    #[test]
+    fn encoding_follows_the_extension_bom_and_contents() {
+        assert_eq!(Encoding::detect("A.M3U8", b"\xe9"), Encoding::Utf8);
+        assert_eq!(Encoding::detect("a.m3u", b"\xef\xbb\xbfa"), Encoding::Utf8);
+        assert_eq!(
+            Encoding::detect("a.m3u", "caf\u{e9}".as_bytes()),
+            Encoding::Utf8
+        );
+        assert_eq!(Encoding::detect("a.m3u", b"caf\xe9"), Encoding::Windows1252);
+        assert_eq!(
+            Encoding::Windows1252.decode(b"caf\xe9 \x80"),
+            "caf\u{e9} \u{20ac}"
+        );
+        assert_eq!(Encoding::Utf8.decode(b"\xef\xbb\xbfa.flac"), "a.flac");
+    }
+}
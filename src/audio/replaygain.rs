@@ -0,0 +1,390 @@
+//! ReplayGain tag parsing and per track gain computation.
+//!
+//! Tags are picked up from FLAC Vorbis comments, ID3v2 `TXXX` frames and the
+//! Opus style `R128_*_GAIN` tags. The resulting gain is applied to the decoded
+//! PCM before it is pushed to the I2S DMA buffer.
+
+use heapless::String;
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::{UNITY_Q16, apply_gain_q16, db_to_linear, linear_to_q16};
+
+/// ReplayGain 2.0 reference is -18 LUFS, R128 tags are relative to -23 LUFS.
+const R128_TO_REPLAY_GAIN_DB: f32 = 5.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplayGainMode {
+    Off,
+    #[default]
+    Track,
+    Album,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGainInfo {
+    pub track_gain_db: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain_db: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// Extra gain added on top of the tagged gain.
+    pub preamp_db: f32,
+    /// Gain used for files without any ReplayGain tags.
+    pub fallback_gain_db: f32,
+    /// Lower the gain so that `peak * gain` never exceeds full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Track,
+            preamp_db: 0.0,
+            fallback_gain_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl ReplayGainInfo {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain_db.is_none() && self.album_gain_db.is_none()
+    }
+
+    /// Parses a single `key`/`value` tag pair, returns true if it was a gain tag.
+    pub fn parse_tag(&mut self, key: &str, value: &str) -> bool {
+        let key = key.trim();
+        if key.eq_ignore_ascii_case("REPLAYGAIN_TRACK_GAIN") {
+            self.track_gain_db = parse_gain_db(value);
+        } else if key.eq_ignore_ascii_case("REPLAYGAIN_TRACK_PEAK") {
+            self.track_peak = parse_peak(value);
+        } else if key.eq_ignore_ascii_case("REPLAYGAIN_ALBUM_GAIN") {
+            self.album_gain_db = parse_gain_db(value);
+        } else if key.eq_ignore_ascii_case("REPLAYGAIN_ALBUM_PEAK") {
+            self.album_peak = parse_peak(value);
+        } else if key.eq_ignore_ascii_case("R128_TRACK_GAIN") {
+            // Explicit REPLAYGAIN_* tags take precedence over R128 ones.
+            if self.track_gain_db.is_none() {
+                self.track_gain_db = parse_r128_gain_db(value);
+            }
+        } else if key.eq_ignore_ascii_case("R128_ALBUM_GAIN") {
+            if self.album_gain_db.is_none() {
+                self.album_gain_db = parse_r128_gain_db(value);
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Scans an ID3v2 tag at the start of `file_bytes` for ReplayGain `TXXX` frames.
+    pub fn parse_id3v2(&mut self, file_bytes: &[u8]) {
+        if file_bytes.len() < 10 || &file_bytes[..3] != b"ID3" {
+            return;
+        }
+        let major_version = file_bytes[3];
+        let flags = file_bytes[5];
+        if !(3..=4).contains(&major_version) {
+            // ID3v2.2 uses three letter frame ids, nobody writes gain tags there.
+            return;
+        }
+        let tag_end = (10 + syncsafe_u32(&file_bytes[6..10]) as usize).min(file_bytes.len());
+        let mut pos = 10;
+        if flags & 0x40 != 0 && pos + 4 <= tag_end {
+            let ext_size = if major_version == 4 {
+                syncsafe_u32(&file_bytes[pos..pos + 4]) as usize
+            } else {
+                be_u32(&file_bytes[pos..pos + 4]) as usize + 4
+            };
+            // A size that runs past the tag, or past `usize` on 32 bit
+            // targets, means the tag is broken.
+            match pos.checked_add(ext_size) {
+                Some(ext_end) if ext_end <= tag_end => pos = ext_end,
+                _ => return,
+            }
+        }
+
+        while pos + 10 <= tag_end {
+            let frame_id = &file_bytes[pos..pos + 4];
+            if frame_id[0] == 0 {
+                // Reached the padding.
+                break;
+            }
+            let frame_size = if major_version == 4 {
+                syncsafe_u32(&file_bytes[pos + 4..pos + 8])
+            } else {
+                be_u32(&file_bytes[pos + 4..pos + 8])
+            } as usize;
+            let body_start = pos + 10;
+            let Some(body_end) = body_start
+                .checked_add(frame_size)
+                .filter(|&body_end| body_end <= tag_end)
+            else {
+                break;
+            };
+            if frame_id == b"TXXX" {
+                self.parse_txxx(&file_bytes[body_start..body_end]);
+            }
+            pos = body_end;
+        }
+    }
+
+    fn parse_txxx(&mut self, body: &[u8]) {
+        let Some((&encoding, text)) = body.split_first() else {
+            return;
+        };
+        let (description, value) = match encoding {
+            // ISO-8859-1 and UTF-8 are single byte terminated.
+            0 | 3 => match text.iter().position(|&b| b == 0) {
+                Some(idx) => (
+                    decode_id3_text(encoding, &text[..idx]),
+                    decode_id3_text(encoding, &text[idx + 1..]),
+                ),
+                None => return,
+            },
+            // UTF-16 strings are terminated by a 16 bit zero.
+            1 | 2 => match text.chunks_exact(2).position(|unit| unit == [0, 0]) {
+                Some(idx) => (
+                    decode_id3_text(encoding, &text[..idx * 2]),
+                    decode_id3_text(encoding, &text[idx * 2 + 2..]),
+                ),
+                None => return,
+            },
+            _ => return,
+        };
+        self.parse_tag(&description, &value);
+    }
+}
+
+/// Runtime ReplayGain state for the stream being played.
+pub struct ReplayGain {
+    settings: ReplayGainSettings,
+    info: ReplayGainInfo,
+    gain_q16: i32,
+    clipped: u32,
+}
+
+impl ReplayGain {
+    pub fn new(settings: ReplayGainSettings) -> Self {
+        Self {
+            settings,
+            info: ReplayGainInfo::default(),
+            gain_q16: UNITY_Q16,
+            clipped: 0,
+        }
+    }
+
+    pub fn settings(&self) -> ReplayGainSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: ReplayGainSettings) {
+        self.settings = settings;
+        self.gain_q16 = linear_to_q16(self.linear_gain());
+    }
+
+    pub fn set_track_info(&mut self, info: ReplayGainInfo) {
+        self.info = info;
+        self.gain_q16 = linear_to_q16(self.linear_gain());
+    }
+
+    /// Linear gain for the current settings, with clipping prevention applied.
+    pub fn linear_gain(&self) -> f32 {
+        let (gain_db, peak) = match self.settings.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (
+                self.info.track_gain_db.or(self.info.album_gain_db),
+                self.info.track_peak.or(self.info.album_peak),
+            ),
+            ReplayGainMode::Album => (
+                self.info.album_gain_db.or(self.info.track_gain_db),
+                self.info.album_peak.or(self.info.track_peak),
+            ),
+        };
+        let gain_db = gain_db.unwrap_or(self.settings.fallback_gain_db) + self.settings.preamp_db;
+        let mut gain = db_to_linear(gain_db);
+        if self.settings.prevent_clipping
+            && let Some(peak) = peak.filter(|&peak| peak > 0.0)
+        {
+            gain = gain.min(1.0 / peak);
+        }
+        gain
+    }
+}
+
+impl Processor for ReplayGain {
+    fn configure(&mut self, _format: StreamFormat) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        self.clipped += apply_gain_q16(block.samples_mut(), self.gain_q16);
+    }
+
+    fn take_clipped_samples(&mut self) -> u32 {
+        core::mem::take(&mut self.clipped)
+    }
+}
+
+fn parse_gain_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().trim_start_matches('+').parse().ok()
+}
+
+fn parse_peak(value: &str) -> Option<f32> {
+    value.trim().parse().ok().filter(|peak: &f32| *peak >= 0.0)
+}
+
+/// R128 gains are Q7.8 fixed point integers in dB.
+fn parse_r128_gain_db(value: &str) -> Option<f32> {
+    let q78: i16 = value.trim().parse().ok()?;
+    Some(q78 as f32 / 256.0 + R128_TO_REPLAY_GAIN_DB)
+}
+
+fn syncsafe_u32(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as u32)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Decodes an ID3 text field, keeping only the ASCII subset since gain tags
+/// never contain anything else.
+fn decode_id3_text(encoding: u8, bytes: &[u8]) -> String<64> {
+    let mut out = String::new();
+    match encoding {
+        1 | 2 => {
+            let (mut little_endian, mut units) = (false, bytes);
+            match bytes {
+                [0xff, 0xfe, rest @ ..] => (little_endian, units) = (true, rest),
+                [0xfe, 0xff, rest @ ..] => units = rest,
+                _ => {}
+            }
+            for unit in units.chunks_exact(2) {
+                let unit = if little_endian {
+                    u16::from_le_bytes([unit[0], unit[1]])
+                } else {
+                    u16::from_be_bytes([unit[0], unit[1]])
+                };
+                if unit == 0 || unit >= 0x80 {
+                    break;
+                }
+                if out.push(unit as u8 as char).is_err() {
+                    break;
+                }
+            }
+        }
+        _ => {
+            for &b in bytes.iter().take_while(|&&b| b != 0 && b < 0x80) {
+                if out.push(b as char).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// An ID3v2.3 tag holding `frames`, after `extended_header` if there is one.
+    fn id3v23(extended_header: &[u8], frames: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = Vec::from(extended_header);
+        for (id, frame) in frames {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(frame);
+        }
+        let flags = if extended_header.is_empty() { 0 } else { 0x40 };
+        let size = body.len() as u32;
+        let mut tag = Vec::from(*b"ID3");
+        tag.extend_from_slice(&[3, 0, flags]);
+        tag.extend((0..4).rev().map(|i| (size >> (7 * i)) as u8 & 0x7f));
+        tag.extend_from_slice(&body);
+        tag
+    }
+
+    const TRACK_GAIN: &[u8] = b"\0REPLAYGAIN_TRACK_GAIN\0-6.50 dB";
+
+    #[test]
+    fn reads_gain_from_txxx_frames() {
+        let mut info = ReplayGainInfo::default();
+        info.parse_id3v2(&id3v23(
+            &[],
+            &[
+                (b"TIT2", b"\0Title"),
+                (b"TXXX", TRACK_GAIN),
+                (b"TXXX", b"\0REPLAYGAIN_TRACK_PEAK\x000.988"),
+            ],
+        ));
+        assert_eq!(info.track_gain_db, Some(-6.5));
+        assert_eq!(info.track_peak, Some(0.988));
+    }
+
+    #[test]
+    fn skips_the_extended_header() {
+        let mut info = ReplayGainInfo::default();
+        // Size, flags and padding size, the size leaves itself out in v2.3.
+        let extended_header = [0, 0, 0, 6, 0, 0, 0, 0, 0, 0];
+        info.parse_id3v2(&id3v23(&extended_header, &[(b"TXXX", TRACK_GAIN)]));
+        assert_eq!(info.track_gain_db, Some(-6.5));
+
+        let mut info = ReplayGainInfo::default();
+        let oversized = [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0];
+        info.parse_id3v2(&id3v23(&oversized, &[(b"TXXX", TRACK_GAIN)]));
+        assert!(info.is_empty());
+    }
+
+    #[test]
+    fn stops_at_a_frame_larger_than_the_tag() {
+        let mut tag = id3v23(&[], &[(b"TXXX", TRACK_GAIN), (b"TXXX", TRACK_GAIN)]);
+        // The second frame claims 4 GiB.
+        let second = 10 + 10 + TRACK_GAIN.len();
+        tag[second + 4..second + 8].copy_from_slice(&[0xff; 4]);
+        let mut info = ReplayGainInfo::default();
+        info.parse_id3v2(&tag);
+        assert_eq!(info.track_gain_db, Some(-6.5));
+    }
+
+    #[test]
+    fn reads_utf16_txxx_frames() {
+        let mut frame = Vec::from([1u8]);
+        for text in ["REPLAYGAIN_ALBUM_GAIN", "+2.25 dB"] {
+            frame.extend_from_slice(&[0xff, 0xfe]);
+            frame.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            frame.extend_from_slice(&[0, 0]);
+        }
+        let mut info = ReplayGainInfo::default();
+        info.parse_id3v2(&id3v23(&[], &[(b"TXXX", &frame)]));
+        assert_eq!(info.album_gain_db, Some(2.25));
+    }
+
+    #[test]
+    fn r128_gains_are_moved_to_the_replaygain_reference() {
+        let mut info = ReplayGainInfo::default();
+        // -10 dB relative to -23 LUFS is -5 dB relative to -18 LUFS.
+        assert!(info.parse_tag("R128_TRACK_GAIN", "-2560"));
+        assert_eq!(info.track_gain_db, Some(-5.0));
+
+        // An explicit REPLAYGAIN tag wins whichever comes first.
+        assert!(info.parse_tag("REPLAYGAIN_ALBUM_GAIN", "-7 dB"));
+        assert!(info.parse_tag("R128_ALBUM_GAIN", "0"));
+        assert_eq!(info.album_gain_db, Some(-7.0));
+    }
+}
@@ -0,0 +1,550 @@
+//! Picking playback up where it was after a power cycle.
+//!
+//! `player_task` publishes a [`ResumePoint`] to [`RESUME_POINT`] whenever the
+//! track, state or volume changes and every [`RESUME_POSITION_STEP_MS`] of
+//! playback. [`ResumeWriter`] follows it and [`PLAYER_QUEUE`] and writes the
+//! session to the root of the SD card, [`load`] and [`restore`] bring it back
+//! at boot.
+//!
+//! Writes take turns between the two [`SESSION_FILE_NAMES`], each stamped
+//! with a sequence number and a CRC. A power cut in the middle of a write
+//! only loses that write, [`load`] falls back on the other file.
+//!
+//! The card is only written when something changed. A burst of changes to the
+//! queue, track, state or volume goes out as one write [`SETTLE_MS`] after
+//! the first of them, and a position that is all that moved is written at
+//! most every [`POSITION_SAVE_INTERVAL_S`], which is also about how much of
+//! a track a power cut can lose.
+
+use alloc::vec::Vec;
+
+use defmt::info;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(target_os = "none")]
+use embedded_sdmmc::Mode as FileMode;
+
+#[cfg(target_os = "none")]
+use crate::DirectoryType;
+use crate::audio::player::{PlayerCommand, QUEUE_CAPACITY, TrackRef};
+use crate::audio::playlist::{EnqueueReport, PATH_CAPACITY};
+use crate::audio::status::PlaybackState;
+use crate::audio::volume::DEFAULT_VOLUME_DB;
+use crate::audio::{FileInfo, PLAYER_COMMANDS, PLAYER_QUEUE, QueueReceiver};
+
+/// Written in turn, a session with an even sequence number goes to the first.
+pub const SESSION_FILE_NAMES: [&str; 2] = ["SESSION0.BIN", "SESSION1.BIN"];
+const SESSION_MAGIC: &[u8; 8] = b"OKJASES5";
+/// Sequence number, volume, position, current track, state and track count.
+const SESSION_HEADER_SIZE: usize = 4 + 4 + 8 + 1 + 1 + 1;
+/// CUE track and path length ahead of every path.
+const SESSION_TRACK_HEADER_SIZE: usize = 2;
+/// Speed after every path.
+const SESSION_TRACK_TRAILER_SIZE: usize = 4;
+/// CRC-32 of everything before it, at the very end.
+const SESSION_CRC_SIZE: usize = 4;
+const MAX_SESSION_BYTES: usize = SESSION_MAGIC.len()
+    + SESSION_HEADER_SIZE
+    + QUEUE_CAPACITY * (SESSION_TRACK_HEADER_SIZE + PATH_CAPACITY + SESSION_TRACK_TRAILER_SIZE)
+    + SESSION_CRC_SIZE;
+/// Stands in for `None` in the current track and CUE track bytes.
+const NO_INDEX: u8 = u8::MAX;
+/// Stands in for `None` in a track's speed, no track plays at it.
+const NO_SPEED: f32 = 0.0;
+
+/// How far playback moves between two [`ResumePoint`]s.
+pub const RESUME_POSITION_STEP_MS: u64 = 1_000;
+/// How long after a change to the queue, track, state or volume it is written.
+pub const SETTLE_MS: u64 = 3_000;
+/// Shortest time between two writes that only move the position.
+pub const POSITION_SAVE_INTERVAL_S: u64 = 30;
+/// Number of tasks that can hold a [`RESUME_POINT`] receiver at the same time.
+pub const RESUME_RECEIVERS: usize = 1;
+
+pub static RESUME_POINT: Watch<CriticalSectionRawMutex, ResumePoint, RESUME_RECEIVERS> =
+    Watch::new();
+
+/// Where the player is, everything but the queue itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResumePoint {
+    /// Queue index of the current track.
+    pub current: Option<usize>,
+    /// Position coming out of the DAC.
+    pub position_ms: u64,
+    pub volume_db: f32,
+    pub state: PlaybackState,
+}
+
+impl Default for ResumePoint {
+    fn default() -> Self {
+        Self {
+            current: None,
+            position_ms: 0,
+            volume_db: DEFAULT_VOLUME_DB,
+            state: PlaybackState::Stopped,
+        }
+    }
+}
+
+impl ResumePoint {
+    /// Same track, state and volume, wherever in the track.
+    fn same_place(&self, other: &Self) -> bool {
+        self.current == other.current
+            && self.volume_db == other.volume_db
+            && self.state == other.state
+    }
+
+    /// Publishes unless only the position changed, and by less than
+    /// [`RESUME_POSITION_STEP_MS`].
+    pub(crate) fn publish(&self) {
+        RESUME_POINT
+            .sender()
+            .send_if_modified(|published| match published {
+                Some(published)
+                    if published.same_place(self)
+                        && published.position_ms.abs_diff(self.position_ms)
+                            < RESUME_POSITION_STEP_MS =>
+                {
+                    false
+                }
+                _ => {
+                    *published = Some(*self);
+                    true
+                }
+            });
+    }
+}
+
+/// A queued track as it was last written to the card.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedTrack {
+    /// As the track's [`FileInfo`] names it.
+    pub path: heapless::String<PATH_CAPACITY>,
+    pub cue_track: Option<u8>,
+    pub speed: Option<f32>,
+}
+
+/// A session as it was last written to the card.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    /// One more with every write, the highest valid one is the latest.
+    pub sequence: u32,
+    pub tracks: Vec<SavedTrack>,
+    pub point: ResumePoint,
+}
+
+impl Session {
+    /// Tracks whose path does not fit [`PATH_CAPACITY`] are left out, and
+    /// with them the current track if it is one of them.
+    fn encode(sequence: u32, tracks: &[TrackRef], point: &ResumePoint) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAX_SESSION_BYTES);
+        bytes.extend_from_slice(SESSION_MAGIC);
+        bytes.extend_from_slice(&sequence.to_le_bytes());
+        bytes.extend_from_slice(&point.volume_db.to_le_bytes());
+        bytes.extend_from_slice(&point.position_ms.to_le_bytes());
+        let current_at = bytes.len();
+        let state = match point.state {
+            PlaybackState::Stopped => 0,
+            PlaybackState::Playing => 1,
+            PlaybackState::Paused => 2,
+        };
+        bytes.extend_from_slice(&[NO_INDEX, state, 0]);
+        let mut count = 0;
+        for (index, track) in tracks.iter().enumerate() {
+            let path = track.path();
+            if path.len() > PATH_CAPACITY {
+                info!(
+                    "Not saving a track with a path over {} bytes: {}",
+                    PATH_CAPACITY, path
+                );
+                continue;
+            }
+            if point.current == Some(index) {
+                bytes[current_at] = count;
+            }
+            bytes.push(track.cue_track.unwrap_or(NO_INDEX));
+            bytes.push(path.len() as u8);
+            bytes.extend_from_slice(path.as_bytes());
+            bytes.extend_from_slice(&track.speed.unwrap_or(NO_SPEED).to_le_bytes());
+            count += 1;
+        }
+        bytes[current_at + 2] = count;
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// `None` unless the CRC matches and the header is intact.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (bytes, crc) = bytes.split_last_chunk::<SESSION_CRC_SIZE>()?;
+        if crc32(bytes) != u32::from_le_bytes(*crc) {
+            return None;
+        }
+        let header = bytes.strip_prefix(SESSION_MAGIC)?;
+        let (header, mut rest) = header.split_at_checked(SESSION_HEADER_SIZE)?;
+        let state = match header[17] {
+            1 => PlaybackState::Playing,
+            2 => PlaybackState::Paused,
+            _ => PlaybackState::Stopped,
+        };
+        let point = ResumePoint {
+            current: Some(header[16] as usize).filter(|&index| index != NO_INDEX as usize),
+            position_ms: u64::from_le_bytes(header[8..16].try_into().ok()?),
+            volume_db: f32::from_le_bytes(header[4..8].try_into().ok()?),
+            state,
+        };
+        let mut tracks = Vec::new();
+        for _ in 0..header[18] {
+            let Some((&[cue_track, len], tail)) = rest.split_first_chunk() else {
+                break;
+            };
+            let Some((path, tail)) = tail.split_at_checked(len as usize) else {
+                break;
+            };
+            let Some((speed, tail)) = tail.split_first_chunk::<SESSION_TRACK_TRAILER_SIZE>() else {
+                break;
+            };
+            let Some(path) = core::str::from_utf8(path)
+                .ok()
+                .and_then(|path| heapless::String::try_from(path).ok())
+            else {
+                break;
+            };
+            let speed = f32::from_le_bytes(*speed);
+            tracks.push(SavedTrack {
+                path,
+                cue_track: Some(cue_track).filter(|&cue_track| cue_track != NO_INDEX),
+                speed: Some(speed).filter(|&speed| speed != NO_SPEED),
+            });
+            rest = tail;
+        }
+        Some(Self {
+            sequence: u32::from_le_bytes(header[..4].try_into().ok()?),
+            tracks,
+            point,
+        })
+    }
+}
+
+/// CRC-32 as zip and PNG use it.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The latest of the sessions that could be read.
+fn newest(sessions: impl IntoIterator<Item = Option<Session>>) -> Option<Session> {
+    sessions
+        .into_iter()
+        .flatten()
+        .max_by_key(|session| session.sequence)
+}
+
+/// Reads the latest intact session saved on the card, if there is one.
+#[cfg(target_os = "none")]
+pub fn load(dir: &DirectoryType) -> Option<Session> {
+    let session = newest(SESSION_FILE_NAMES.map(|file_name| read_session(dir, file_name)));
+    if let Some(session) = &session {
+        info!(
+            "Loaded session {} of {} tracks",
+            session.sequence,
+            session.tracks.len()
+        );
+    }
+    session
+}
+
+#[cfg(target_os = "none")]
+fn read_session(dir: &DirectoryType, file_name: &str) -> Option<Session> {
+    let file = match dir.open_file_in_dir(file_name, FileMode::ReadOnly) {
+        Ok(file) => file,
+        Err(e) => {
+            info!(
+                "No saved session in {}: {}",
+                file_name,
+                defmt::Debug2Format(&e)
+            );
+            return None;
+        }
+    };
+    let mut bytes = alloc::vec![0; MAX_SESSION_BYTES];
+    let mut len = 0;
+    while !file.is_eof() && len < bytes.len() {
+        match file.read(&mut bytes[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) => {
+                info!("Error reading saved session: {}", defmt::Debug2Format(&e));
+                return None;
+            }
+        }
+    }
+    let session = Session::decode(&bytes[..len]);
+    if session.is_none() {
+        info!("Ignoring the damaged saved session in {}", file_name);
+    }
+    session
+}
+
+/// Queues the saved tracks `open` finds and puts the volume, current track
+/// and position back. The track carries on playing if it was playing and
+/// `auto_resume` is set, otherwise it waits paused for Play. A session that
+/// was stopped only gets its queue back.
+pub async fn restore(
+    session: &Session,
+    mut open: impl FnMut(&str) -> Option<FileInfo>,
+    auto_resume: bool,
+) -> EnqueueReport {
+    let point = session.point;
+    PLAYER_COMMANDS
+        .send(PlayerCommand::SetVolume(point.volume_db))
+        .await;
+    let mut report = EnqueueReport::default();
+    let mut current = None;
+    for (index, saved) in session.tracks.iter().enumerate() {
+        match open(&saved.path) {
+            Some(file) => {
+                if point.current == Some(index) {
+                    current = Some(report.queued);
+                }
+                let track = TrackRef {
+                    cue_track: saved.cue_track,
+                    ..TrackRef::new(file)
+                }
+                .with_speed(saved.speed);
+                PLAYER_COMMANDS.send(PlayerCommand::Enqueue(track)).await;
+                report.queued += 1;
+            }
+            None => {
+                info!("Saved track missing: {}", saved.path.as_str());
+                report.missing += 1;
+            }
+        }
+    }
+    if let Some(index) = current
+        && point.state != PlaybackState::Stopped
+    {
+        PLAYER_COMMANDS
+            .send(PlayerCommand::Restore {
+                index,
+                position_ms: point.position_ms,
+                play: auto_resume && point.state == PlaybackState::Playing,
+            })
+            .await;
+    }
+    report
+}
+
+type ResumePointReceiver =
+    Receiver<'static, CriticalSectionRawMutex, ResumePoint, RESUME_RECEIVERS>;
+
+/// Decides when the session is worth writing to the card.
+pub struct ResumeWriter {
+    queue: QueueReceiver,
+    point: ResumePointReceiver,
+    tracks: heapless::Vec<TrackRef, QUEUE_CAPACITY>,
+    latest: ResumePoint,
+    saved_tracks: heapless::Vec<TrackRef, QUEUE_CAPACITY>,
+    /// `None` until the first write.
+    saved: Option<ResumePoint>,
+    /// First change to the queue, track, state or volume since the last write.
+    changed_at: Option<Instant>,
+    last_write: Instant,
+    /// Of the newest session on the card.
+    sequence: u32,
+}
+
+impl ResumeWriter {
+    /// `sequence` is that of the session [`load`] found, 0 if there was none.
+    /// `None` if every [`PLAYER_QUEUE`] or [`RESUME_POINT`] receiver is taken.
+    pub fn new(sequence: u32) -> Option<Self> {
+        Some(Self {
+            queue: PLAYER_QUEUE.receiver()?,
+            point: RESUME_POINT.receiver()?,
+            tracks: heapless::Vec::new(),
+            latest: ResumePoint::default(),
+            saved_tracks: heapless::Vec::new(),
+            saved: None,
+            changed_at: None,
+            last_write: Instant::now(),
+            sequence,
+        })
+    }
+
+    /// Waits until the session has changed and a write is due. Dropping it
+    /// early loses nothing.
+    pub async fn wait_due(&mut self) {
+        loop {
+            let deadline = self.deadline();
+            let changes = select(self.queue.changed(), self.point.changed());
+            let change = match deadline {
+                Some(deadline) => match select(Timer::at(deadline), changes).await {
+                    Either::First(()) => return,
+                    Either::Second(change) => change,
+                },
+                None => changes.await,
+            };
+            let now = Instant::now();
+            match change {
+                Either::First(tracks) => {
+                    if tracks != self.saved_tracks {
+                        self.changed_at.get_or_insert(now);
+                    }
+                    self.tracks = tracks;
+                }
+                Either::Second(point) => {
+                    if !self.saved.is_some_and(|saved| saved.same_place(&point)) {
+                        self.changed_at.get_or_insert(now);
+                    }
+                    self.latest = point;
+                }
+            }
+        }
+    }
+
+    /// Writes the latest session over the older of the two on the card.
+    #[cfg(target_os = "none")]
+    pub fn save(&mut self, dir: &DirectoryType) {
+        let sequence = self.sequence.wrapping_add(1);
+        let bytes = Session::encode(sequence, &self.tracks, &self.latest);
+        // Counts as written even if it failed, a bad card is not retried
+        // any sooner than a good one is written.
+        self.saved = Some(self.latest);
+        self.saved_tracks = self.tracks.clone();
+        self.changed_at = None;
+        self.last_write = Instant::now();
+        let file_name = SESSION_FILE_NAMES[sequence as usize % SESSION_FILE_NAMES.len()];
+        let file = match dir.open_file_in_dir(file_name, FileMode::ReadWriteCreateOrTruncate) {
+            Ok(file) => file,
+            Err(e) => {
+                info!("Error creating saved session: {}", defmt::Debug2Format(&e));
+                return;
+            }
+        };
+        match file.write(&bytes).and_then(|_| file.close()) {
+            // A failed write goes to the same file next time, so the newest
+            // good session is never the one overwritten.
+            Ok(()) => self.sequence = sequence,
+            Err(e) => info!("Error writing saved session: {}", defmt::Debug2Format(&e)),
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        if let Some(changed_at) = self.changed_at {
+            return Some(changed_at + Duration::from_millis(SETTLE_MS));
+        }
+        self.saved
+            .filter(|saved| saved.position_ms != self.latest.position_ms)
+            .map(|_| self.last_write + Duration::from_secs(POSITION_SAVE_INTERVAL_S))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(file_name: &'static str) -> TrackRef {
+        TrackRef::new(FileInfo {
+            file_name,
+            file_bytes: &[],
+        })
+    }
+
+    fn paths(session: &Session) -> Vec<&str> {
+        session
+            .tracks
+            .iter()
+            .map(|track| track.path.as_str())
+            .collect()
+    }
+
+    const POINT: ResumePoint = ResumePoint {
+        current: Some(1),
+        position_ms: 83_250,
+        volume_db: -12.5,
+        state: PlaybackState::Paused,
+    };
+
+    #[test]
+    fn a_session_comes_back_as_it_was_saved() {
+        let tracks = [
+            track("/a/1.flac"),
+            track("/a/2.flac").with_speed(Some(1.25)),
+            TrackRef::cue(track("/b.flac").file, 3),
+        ];
+        let session = Session::decode(&Session::encode(7, &tracks, &POINT)).unwrap();
+        assert_eq!(session.sequence, 7);
+        assert_eq!(session.point, POINT);
+        assert_eq!(paths(&session), ["/a/1.flac", "/a/2.flac", "/b.flac"]);
+        let speeds: Vec<_> = session.tracks.iter().map(|track| track.speed).collect();
+        assert_eq!(speeds, [None, Some(1.25), None]);
+        let cue_tracks: Vec<_> = session.tracks.iter().map(|track| track.cue_track).collect();
+        assert_eq!(cue_tracks, [None, None, Some(3)]);
+    }
+
+    #[test]
+    fn an_empty_stopped_session_round_trips() {
+        let point = ResumePoint::default();
+        let session = Session::decode(&Session::encode(0, &[], &point)).unwrap();
+        assert_eq!(session.point, point);
+        assert!(session.tracks.is_empty());
+    }
+
+    #[test]
+    fn a_path_too_long_to_save_is_left_out_and_the_current_index_follows() {
+        let long = "/x".repeat(PATH_CAPACITY).leak();
+        let tracks = [track(long), track("/a.flac"), track("/b.flac")];
+        let point = ResumePoint {
+            current: Some(2),
+            ..POINT
+        };
+        let session = Session::decode(&Session::encode(1, &tracks, &point)).unwrap();
+        assert_eq!(paths(&session), ["/a.flac", "/b.flac"]);
+        assert_eq!(session.point.current, Some(1));
+        let point = ResumePoint {
+            current: Some(0),
+            ..POINT
+        };
+        let session = Session::decode(&Session::encode(1, &tracks, &point)).unwrap();
+        assert_eq!(session.point.current, None);
+    }
+
+    #[test]
+    fn a_damaged_session_is_rejected() {
+        let bytes = Session::encode(1, &[track("/a.flac")], &POINT);
+        assert!(Session::decode(&bytes).is_some());
+        let mut flipped = bytes.clone();
+        flipped[SESSION_MAGIC.len() + 6] ^= 1;
+        assert_eq!(Session::decode(&flipped), None);
+        // Cut off by a power cut halfway through the write.
+        assert_eq!(Session::decode(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Session::decode(&[]), None);
+
+        // Intact, but written by something else.
+        let mut bad_magic = bytes[..bytes.len() - SESSION_CRC_SIZE].to_vec();
+        bad_magic[0] ^= 1;
+        let crc = crc32(&bad_magic);
+        bad_magic.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(Session::decode(&bad_magic), None);
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn the_newest_intact_session_wins() {
+        let session =
+            |sequence| Session::decode(&Session::encode(sequence, &[track("/a.flac")], &POINT));
+        assert_eq!(newest([session(4), session(5)]).unwrap().sequence, 5);
+        assert_eq!(newest([session(9), session(8)]).unwrap().sequence, 9);
+        assert_eq!(newest([None, session(2)]).unwrap().sequence, 2);
+        assert_eq!(newest([None, None]), None);
+    }
+}
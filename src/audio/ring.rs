@@ -0,0 +1,288 @@
+//! Lock-free PCM ring between the decoder and the I2S feeder.
+//!
+//! `player_task` decodes on the APP core and `output_task` drains the ring into
+//! the I2S DMA from the PRO core. The ring is a queue of fixed size slots: the
+//! decoder and the DSP chain work in place in a [`WriteSlot`], and
+//! [`PcmRing::pop_into`] packs the samples straight into the DMA buffer, so
+//! the PCM is never copied in between. Each side only ever stores its own
+//! counters, and stores them with `Release` after touching the samples, so
+//! neither side needs a lock to see a consistent ring.
+//!
+//! The producer also opens and closes the ring. A closed ring is faded out by
+//! the consumer as it plays it, so a pause or a skip is heard at once rather
+//! than after everything already queued, and then held until it opens again.
+//! [`PcmRing::flush`] drops what is held when it no longer belongs next to
+//! what comes after it.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::audio::output_format::OutputFormat;
+
+/// Samples in one slot, `player_task` fills it over as many decode and DSP
+/// passes as it takes.
+pub const SLOT_SAMPLES: usize = 1024;
+/// 4096 stereo frames in all, about 85 ms at 48 kHz.
+pub const SLOTS: usize = 8;
+
+pub static PCM_RING: PcmRing<SLOTS> = PcmRing::new();
+
+/// `N` has to be a power of two so the counters can wrap freely.
+pub struct PcmRing<const N: usize> {
+    slots: [UnsafeCell<[i16; SLOT_SAMPLES]>; N],
+    /// Samples committed to each slot.
+    lengths: [AtomicUsize; N],
+    /// Slots ever committed, only the producer stores it.
+    committed: AtomicUsize,
+    /// Slots ever fully read, only the consumer stores it.
+    released: AtomicUsize,
+    /// Samples already read from the oldest committed slot, consumer only.
+    read_offset: AtomicUsize,
+    /// Samples ever committed and ever read, for [`PcmRing::len`].
+    samples_in: AtomicUsize,
+    samples_out: AtomicUsize,
+    /// Whether the output should play the ring, only the producer stores it.
+    open: AtomicBool,
+    /// Faded out all the way and reading nothing, only the consumer stores it.
+    silent: AtomicBool,
+    /// `committed` at the last flush, the consumer drops every slot before it.
+    flushed: AtomicUsize,
+    /// Raised by the consumer whenever it frees a slot.
+    room: Signal<CriticalSectionRawMutex, ()>,
+    /// Raised by the producer whenever it commits a slot, opens or closes the
+    /// ring or flushes it.
+    data: Signal<CriticalSectionRawMutex, ()>,
+    /// Raised by the consumer when it goes silent.
+    quiet: Signal<CriticalSectionRawMutex, ()>,
+}
+
+// The producer only touches uncommitted slots and the consumer only touches
+// committed ones, the counters keep the two apart.
+unsafe impl<const N: usize> Sync for PcmRing<N> {}
+
+impl<const N: usize> Default for PcmRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PcmRing<N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two());
+        Self {
+            slots: [const { UnsafeCell::new([0; SLOT_SAMPLES]) }; N],
+            lengths: [const { AtomicUsize::new(0) }; N],
+            committed: AtomicUsize::new(0),
+            released: AtomicUsize::new(0),
+            read_offset: AtomicUsize::new(0),
+            samples_in: AtomicUsize::new(0),
+            samples_out: AtomicUsize::new(0),
+            open: AtomicBool::new(false),
+            silent: AtomicBool::new(true),
+            flushed: AtomicUsize::new(0),
+            room: Signal::new(),
+            data: Signal::new(),
+            quiet: Signal::new(),
+        }
+    }
+
+    /// Samples waiting to be read.
+    pub fn len(&self) -> usize {
+        self.samples_in
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.samples_out.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn committed_slots(&self) -> usize {
+        self.committed
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.released.load(Ordering::Acquire))
+    }
+
+    /// Waits for a free slot to decode into. Only `player_task` may call this,
+    /// and only hold one slot at a time.
+    pub(crate) async fn claim(&self) -> WriteSlot<'_, N> {
+        while self.committed_slots() == N {
+            self.room.wait().await;
+        }
+        let index = self.committed.load(Ordering::Relaxed) % N;
+        // SAFETY: the slot is not committed, so the consumer does not touch it,
+        // and the single producer holds no other reference to it.
+        let samples = unsafe { &mut *self.slots[index].get() };
+        WriteSlot {
+            ring: self,
+            index,
+            samples,
+        }
+    }
+
+    /// Opens the ring for the output to play, or closes it to have the output
+    /// fade out and hold what is left. Only `player_task` may call this.
+    pub(crate) fn set_open(&self, open: bool) {
+        if self.open.swap(open, Ordering::AcqRel) != open {
+            self.data.signal(());
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// The output is faded out all the way and reads nothing from the ring.
+    pub fn is_silent(&self) -> bool {
+        self.silent.load(Ordering::Acquire)
+    }
+
+    /// Returns once the output is silent.
+    pub(crate) async fn wait_silent(&self) {
+        while !self.is_silent() {
+            self.quiet.wait().await;
+        }
+    }
+
+    /// Drops everything committed so far and waits for the output to let go
+    /// of it. Only `player_task` may call this, with the output silent.
+    pub(crate) async fn flush(&self) {
+        self.flushed
+            .store(self.committed.load(Ordering::Relaxed), Ordering::Release);
+        self.data.signal(());
+        while self.committed_slots() > 0 {
+            self.room.wait().await;
+        }
+    }
+
+    /// Reports whether the output is silent. Only `output_task` may call this.
+    pub(crate) fn set_silent(&self, silent: bool) {
+        if !self.silent.swap(silent, Ordering::AcqRel) && silent {
+            self.quiet.signal(());
+        }
+    }
+
+    /// Releases the slots a [`PcmRing::flush`] dropped. Only `output_task` may
+    /// call this.
+    pub(crate) fn drop_flushed(&self) {
+        let released = self.released.load(Ordering::Relaxed);
+        // Past N the flush is older than everything still committed.
+        let slots = self.flushed.load(Ordering::Acquire).wrapping_sub(released);
+        if slots == 0 || slots > N {
+            return;
+        }
+        let mut dropped = 0;
+        for slot in 0..slots {
+            dropped += self.lengths[released.wrapping_add(slot) % N].load(Ordering::Relaxed);
+        }
+        dropped -= self.read_offset.load(Ordering::Relaxed);
+        self.read_offset.store(0, Ordering::Relaxed);
+        self.released
+            .store(released.wrapping_add(slots), Ordering::Release);
+        self.samples_out.fetch_add(dropped, Ordering::Release);
+        self.room.signal(());
+    }
+
+    /// Packs as many whole frames as fit into `bytes`, returns how many bytes
+    /// that was. `process` gets the samples in place first, in runs of whole
+    /// frames. Only `output_task` may call this.
+    pub(crate) fn pop_into<F: OutputFormat>(
+        &self,
+        bytes: &mut [u8],
+        mut process: impl FnMut(&mut [i16]),
+    ) -> usize {
+        let mut bytes = bytes;
+        let mut packed = 0;
+        while bytes.len() >= F::BYTES_PER_FRAME && self.committed_slots() > 0 {
+            let released = self.released.load(Ordering::Relaxed);
+            let index = released % N;
+            let length = self.lengths[index].load(Ordering::Relaxed);
+            let offset = self.read_offset.load(Ordering::Relaxed);
+            // SAFETY: the slot is committed, so the producer does not touch it
+            // until it is released below.
+            let slot = unsafe { &mut *self.slots[index].get() };
+            let samples = &mut slot[offset..length];
+            let count = samples
+                .len()
+                .min(bytes.len() / F::BYTES_PER_FRAME * F::CHANNELS);
+            let samples = &mut samples[..count];
+            process(samples);
+            let (out, rest) = bytes.split_at_mut(count * F::BYTES_PER_SAMPLE);
+            for (&sample, out) in samples
+                .iter()
+                .zip(out.chunks_exact_mut(F::BYTES_PER_SAMPLE))
+            {
+                F::pack(sample, out);
+            }
+            bytes = rest;
+            packed += count;
+            if offset + count == length {
+                self.read_offset.store(0, Ordering::Relaxed);
+                self.released
+                    .store(released.wrapping_add(1), Ordering::Release);
+                self.room.signal(());
+            } else {
+                self.read_offset.store(offset + count, Ordering::Relaxed);
+            }
+        }
+        self.samples_out.fetch_add(packed, Ordering::Release);
+        packed * F::BYTES_PER_SAMPLE
+    }
+
+    /// Returns once there is something to read.
+    pub async fn wait_for_data(&self) {
+        while self.is_empty() {
+            self.data.wait().await;
+        }
+    }
+
+    /// Returns once the producer has committed, opened, closed or flushed,
+    /// possibly before this was called.
+    pub(crate) async fn wait_for_producer(&self) {
+        self.data.wait().await;
+    }
+}
+
+/// A free slot of the ring, handed to the decoder and the DSP chain to work in.
+/// Nothing reaches the output until [`WriteSlot::commit`].
+pub struct WriteSlot<'a, const N: usize> {
+    ring: &'a PcmRing<N>,
+    index: usize,
+    samples: &'a mut [i16; SLOT_SAMPLES],
+}
+
+impl<const N: usize> WriteSlot<'_, N> {
+    /// Queues the first `len` samples for output.
+    pub fn commit(self, len: usize) {
+        let len = len.min(SLOT_SAMPLES);
+        if len == 0 {
+            return;
+        }
+        let ring = self.ring;
+        ring.lengths[self.index].store(len, Ordering::Relaxed);
+        ring.samples_in.fetch_add(len, Ordering::Release);
+        ring.committed.store(
+            ring.committed.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Release,
+        );
+        ring.data.signal(());
+    }
+}
+
+impl<const N: usize> Deref for WriteSlot<'_, N> {
+    type Target = [i16];
+
+    fn deref(&self) -> &[i16] {
+        self.samples
+    }
+}
+
+impl<const N: usize> DerefMut for WriteSlot<'_, N> {
+    fn deref_mut(&mut self) -> &mut [i16] {
+        self.samples
+    }
+}
@@ -0,0 +1,221 @@
+//! Background loudness scanning for tracks without ReplayGain tags.
+//!
+//! `player_task` looks untagged tracks up in [`LOUDNESS_CACHE`] and queues the
+//! misses on [`LOUDNESS_SCAN_REQUESTS`]. [`loudness_scan_task`] decodes them
+//! without playing them and stores the result. The cache lives in
+//! [`CACHE_FILE_NAME`] at the root of the SD card, so every card carries the
+//! results for its own files.
+
+use core::cell::RefCell;
+
+use defmt::info;
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embedded_sdmmc::Mode as FileMode;
+
+use crate::DirectoryType;
+use crate::audio::FileInfo;
+use crate::audio::codec::codec::Decoder;
+use crate::audio::loudness::{Loudness, LoudnessMeter};
+use crate::audio::pcm::AudioBuffer;
+
+pub const CACHE_FILE_NAME: &str = "LOUDNESS.BIN";
+const CACHE_MAGIC: &[u8; 8] = b"OKJALUF1";
+const CACHE_ENTRY_SIZE: usize = 16;
+pub const CACHE_CAPACITY: usize = 512;
+
+const SCAN_SAMPLES_PER_CALL: usize = 4096;
+
+pub static LOUDNESS_CACHE: Mutex<CriticalSectionRawMutex, RefCell<LoudnessCache>> =
+    Mutex::new(RefCell::new(LoudnessCache::new()));
+pub static LOUDNESS_SCAN_REQUESTS: Channel<CriticalSectionRawMutex, FileInfo, 4> = Channel::new();
+/// Raised when the cache has entries that are not on the card yet.
+pub static LOUDNESS_CACHE_DIRTY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Identifies a file on the card by name and size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrackKey {
+    pub name_hash: u32,
+    pub file_len: u32,
+}
+
+impl TrackKey {
+    pub fn new(file_name: &str, file_len: usize) -> Self {
+        // FNV-1a
+        let name_hash = file_name.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        });
+        Self {
+            name_hash,
+            file_len: file_len as u32,
+        }
+    }
+}
+
+pub struct LoudnessCache {
+    entries: heapless::Vec<(TrackKey, Loudness), CACHE_CAPACITY>,
+}
+
+impl Default for LoudnessCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoudnessCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: TrackKey) -> Option<Loudness> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| *entry_key == key)
+            .map(|&(_, loudness)| loudness)
+    }
+
+    /// Replaces any entry for `key`, the oldest entry makes room when full.
+    pub fn insert(&mut self, key: TrackKey, loudness: Loudness) {
+        self.entries.retain(|(entry_key, _)| *entry_key != key);
+        if self.entries.is_full() {
+            self.entries.remove(0);
+        }
+        let _ = self.entries.push((key, loudness));
+    }
+
+    fn encode_entry(key: TrackKey, loudness: Loudness) -> [u8; CACHE_ENTRY_SIZE] {
+        let mut bytes = [0; CACHE_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&key.name_hash.to_le_bytes());
+        bytes[4..8].copy_from_slice(&key.file_len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&loudness.integrated_lufs.to_le_bytes());
+        bytes[12..16].copy_from_slice(&loudness.true_peak.to_le_bytes());
+        bytes
+    }
+
+    fn decode_entry(bytes: &[u8]) -> (TrackKey, Loudness) {
+        let word = |i: usize| [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+        (
+            TrackKey {
+                name_hash: u32::from_le_bytes(word(0)),
+                file_len: u32::from_le_bytes(word(4)),
+            },
+            Loudness {
+                integrated_lufs: f32::from_le_bytes(word(8)),
+                true_peak: f32::from_le_bytes(word(12)),
+            },
+        )
+    }
+}
+
+/// Looks up a previous scan of the track.
+pub fn cached_loudness(file_name: &str, file_len: usize) -> Option<Loudness> {
+    let key = TrackKey::new(file_name, file_len);
+    LOUDNESS_CACHE.lock(|cache| cache.borrow().get(key))
+}
+
+/// Replaces the in-memory cache with the one stored on the card, if any.
+pub fn load_cache(dir: &DirectoryType) {
+    let file = match dir.open_file_in_dir(CACHE_FILE_NAME, FileMode::ReadOnly) {
+        Ok(file) => file,
+        Err(e) => {
+            info!("No loudness cache on the card: {}", defmt::Debug2Format(&e));
+            return;
+        }
+    };
+    let mut magic = [0; CACHE_MAGIC.len()];
+    if !matches!(file.read(&mut magic), Ok(n) if n == magic.len() && &magic == CACHE_MAGIC) {
+        info!("Ignoring loudness cache with a bad header");
+        return;
+    }
+    let mut cache = LoudnessCache::new();
+    let mut entry = [0; CACHE_ENTRY_SIZE];
+    while !file.is_eof() {
+        match file.read(&mut entry) {
+            Ok(CACHE_ENTRY_SIZE) => {
+                let (key, loudness) = LoudnessCache::decode_entry(&entry);
+                cache.insert(key, loudness);
+            }
+            Ok(_) => break,
+            Err(e) => {
+                info!("Error reading loudness cache: {}", defmt::Debug2Format(&e));
+                return;
+            }
+        }
+    }
+    info!("Loaded {} loudness cache entries", cache.entries.len());
+    LOUDNESS_CACHE.lock(|shared| *shared.borrow_mut() = cache);
+}
+
+/// Writes the in-memory cache back to the card.
+pub fn save_cache(dir: &DirectoryType) {
+    let file = match dir.open_file_in_dir(CACHE_FILE_NAME, FileMode::ReadWriteCreateOrTruncate) {
+        Ok(file) => file,
+        Err(e) => {
+            info!("Error creating loudness cache: {}", defmt::Debug2Format(&e));
+            return;
+        }
+    };
+    let mut result = file.write(CACHE_MAGIC);
+    // One entry per lock, the critical section must not cover the SD card writes.
+    let mut index = 0;
+    while result.is_ok()
+        && let Some((key, loudness)) =
+            LOUDNESS_CACHE.lock(|cache| cache.borrow().entries.get(index).copied())
+    {
+        result = file.write(&LoudnessCache::encode_entry(key, loudness));
+        index += 1;
+    }
+    if let Err(e) = result.and_then(|_| file.close()) {
+        info!("Error writing loudness cache: {}", defmt::Debug2Format(&e));
+    }
+}
+
+#[embassy_executor::task]
+pub async fn loudness_scan_task() {
+    loop {
+        let FileInfo {
+            file_name,
+            file_bytes,
+        } = LOUDNESS_SCAN_REQUESTS.receive().await;
+        let key = TrackKey::new(file_name, file_bytes.len());
+        if LOUDNESS_CACHE.lock(|cache| cache.borrow().get(key).is_some()) {
+            continue;
+        }
+        info!("Loudness scan start: {}", file_name);
+        match scan(file_name, file_bytes).await {
+            Some(loudness) => {
+                info!(
+                    "Loudness scan done: {}, {} LUFS, true peak {}",
+                    file_name, loudness.integrated_lufs, loudness.true_peak
+                );
+                LOUDNESS_CACHE.lock(|cache| cache.borrow_mut().insert(key, loudness));
+                LOUDNESS_CACHE_DIRTY.signal(());
+            }
+            None => info!("Loudness scan found nothing audible: {}", file_name),
+        }
+    }
+}
+
+/// Decodes the whole track into the meter, yielding after every chunk so
+/// the scan only runs while the other tasks are idle.
+async fn scan(file_name: &'static str, file_bytes: &'static [u8]) -> Option<Loudness> {
+    let mut decoder = Decoder::new(file_name, file_bytes);
+    let format = decoder.stream_format();
+    let mut meter = LoudnessMeter::new(format);
+    let mut samples = [0_i16; SCAN_SAMPLES_PER_CALL];
+    let mut buffer = AudioBuffer::new(&mut samples, 0, format);
+    loop {
+        let decoder_meta = decoder.read_frames(&mut buffer, u64::MAX);
+        if decoder_meta.framesRead == 0 {
+            break;
+        }
+        meter.push(&buffer);
+        yield_now().await;
+    }
+    meter.loudness()
+}
@@ -0,0 +1,298 @@
+//! Spectrum analyzer feed for the visualizer.
+//!
+//! `output_task` pushes the PCM it hands to the DMA, after the fade, into a
+//! [`SpectrumAnalyzer`] so the bars move with what is heard. The analyzer runs a Hann windowed FFT every
+//! [`FFT_HOP`] frames, groups the bins into log spaced bands and applies the
+//! decay and peak hold. The result is published to [`SPECTRUM`], a seqlock over
+//! atomics, so the display task can take a snapshot whenever it draws without
+//! ever blocking the audio path.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use crate::audio::pcm::AudioBuffer;
+
+pub const FFT_SIZE: usize = 512;
+pub const FFT_HOP: usize = FFT_SIZE / 2;
+pub const MAX_BANDS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpectrumConfig {
+    /// Number of bands, at most [`MAX_BANDS`].
+    pub bands: usize,
+    pub min_hz: f32,
+    pub max_hz: f32,
+    /// Level shown as an empty bar.
+    pub floor_db: f32,
+    /// How fast bars fall once the signal drops.
+    pub decay_db_per_s: f32,
+    /// How long a peak marker stays put before it starts falling.
+    pub peak_hold_ms: u32,
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> Self {
+        Self {
+            bands: 32,
+            min_hz: 40.0,
+            max_hz: 16_000.0,
+            floor_db: -72.0,
+            decay_db_per_s: 40.0,
+            peak_hold_ms: 800,
+        }
+    }
+}
+
+/// One frame of the visualizer, levels are scaled from the floor (0) to full scale (255).
+#[derive(Clone, Copy, Debug)]
+pub struct SpectrumSnapshot {
+    /// Bumped on every publish, lets the reader skip redrawing an unchanged frame.
+    pub frame: u32,
+    pub bands: usize,
+    pub levels: [u8; MAX_BANDS],
+    pub peaks: [u8; MAX_BANDS],
+}
+
+/// Single writer, many readers.
+pub struct SpectrumFeed {
+    sequence: AtomicU32,
+    bands: AtomicU8,
+    levels: [AtomicU8; MAX_BANDS],
+    peaks: [AtomicU8; MAX_BANDS],
+}
+
+pub static SPECTRUM: SpectrumFeed = SpectrumFeed::new();
+
+impl Default for SpectrumFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpectrumFeed {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            bands: AtomicU8::new(0),
+            levels: [const { AtomicU8::new(0) }; MAX_BANDS],
+            peaks: [const { AtomicU8::new(0) }; MAX_BANDS],
+        }
+    }
+
+    /// Only `player_task` may call this.
+    fn publish(&self, levels: &[u8], peaks: &[u8]) {
+        // Odd while the write is in progress.
+        let sequence = self.sequence.load(Ordering::Relaxed).wrapping_add(1);
+        self.sequence.store(sequence, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        self.bands.store(levels.len() as u8, Ordering::Relaxed);
+        for (slot, &level) in self.levels.iter().zip(levels) {
+            slot.store(level, Ordering::Relaxed);
+        }
+        for (slot, &peak) in self.peaks.iter().zip(peaks) {
+            slot.store(peak, Ordering::Relaxed);
+        }
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Latest published frame, retries while a publish is in progress.
+    pub fn snapshot(&self) -> SpectrumSnapshot {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let mut snapshot = SpectrumSnapshot {
+                frame: before / 2,
+                bands: self.bands.load(Ordering::Relaxed) as usize,
+                levels: [0; MAX_BANDS],
+                peaks: [0; MAX_BANDS],
+            };
+            for (level, slot) in snapshot.levels.iter_mut().zip(&self.levels) {
+                *level = slot.load(Ordering::Relaxed);
+            }
+            for (peak, slot) in snapshot.peaks.iter_mut().zip(&self.peaks) {
+                *peak = slot.load(Ordering::Relaxed);
+            }
+            core::sync::atomic::fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return snapshot;
+            }
+        }
+    }
+}
+
+pub struct SpectrumAnalyzer {
+    config: SpectrumConfig,
+    sample_rate: u32,
+    window: Vec<f32>,
+    /// `exp(-2πik/N)` for `k < N/2`.
+    twiddles: Vec<(f32, f32)>,
+    /// Ring of the mono mix of the last `FFT_SIZE` frames.
+    input: Vec<f32>,
+    input_position: usize,
+    new_frames: usize,
+    fft: Vec<(f32, f32)>,
+    /// FFT bins `band_edges[i]..band_edges[i + 1]` belong to band `i`.
+    band_edges: Vec<usize>,
+    levels_db: Vec<f32>,
+    peaks_db: Vec<f32>,
+    peak_age_ms: Vec<f32>,
+    levels: Vec<u8>,
+    peaks: Vec<u8>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(config: SpectrumConfig, sample_rate: u32) -> Self {
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * libm::cosf(2.0 * PI * i as f32 / FFT_SIZE as f32))
+            .collect();
+        let twiddles = (0..FFT_SIZE / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f32 / FFT_SIZE as f32;
+                (libm::cosf(angle), libm::sinf(angle))
+            })
+            .collect();
+        let mut analyzer = Self {
+            config,
+            sample_rate,
+            window,
+            twiddles,
+            input: vec![0.0; FFT_SIZE],
+            input_position: 0,
+            new_frames: 0,
+            fft: vec![(0.0, 0.0); FFT_SIZE],
+            band_edges: Vec::new(),
+            levels_db: Vec::new(),
+            peaks_db: Vec::new(),
+            peak_age_ms: Vec::new(),
+            levels: Vec::new(),
+            peaks: Vec::new(),
+        };
+        analyzer.design();
+        analyzer
+    }
+
+    pub fn config(&self) -> &SpectrumConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: SpectrumConfig) {
+        self.config = config;
+        self.design();
+    }
+
+    fn design(&mut self) {
+        let bands = self.config.bands.clamp(1, MAX_BANDS);
+        let bin_hz = self.sample_rate as f32 / FFT_SIZE as f32;
+        let max_hz = self.config.max_hz.min(self.sample_rate as f32 / 2.0);
+        let min_hz = self.config.min_hz.clamp(bin_hz, max_hz);
+        let ratio = max_hz / min_hz;
+
+        self.band_edges.clear();
+        let mut previous = 0;
+        for i in 0..=bands {
+            let hz = min_hz * libm::powf(ratio, i as f32 / bands as f32);
+            let bin = (libm::roundf(hz / bin_hz) as usize).clamp(1, FFT_SIZE / 2);
+            // Low bands narrower than a bin still get one bin each.
+            let bin = if i == 0 { bin } else { bin.max(previous + 1) };
+            self.band_edges.push(bin.min(FFT_SIZE / 2 + 1));
+            previous = bin;
+        }
+        self.levels_db = vec![self.config.floor_db; bands];
+        self.peaks_db = vec![self.config.floor_db; bands];
+        self.peak_age_ms = vec![0.0; bands];
+        self.levels = vec![0; bands];
+        self.peaks = vec![0; bands];
+    }
+
+    /// Feeds interleaved PCM, publishes a new frame to [`SPECTRUM`] every [`FFT_HOP`] frames.
+    pub fn push(&mut self, buffer: &AudioBuffer<'_, i16>) {
+        let channels = buffer.channels();
+        for frame in buffer.frames_iter() {
+            let mono = frame.iter().map(|&s| s as f32).sum::<f32>() / (channels as f32 * 32768.0);
+            self.input[self.input_position] = mono;
+            self.input_position = (self.input_position + 1) % FFT_SIZE;
+            self.new_frames += 1;
+            if self.new_frames == FFT_HOP {
+                self.new_frames = 0;
+                self.analyze();
+                SPECTRUM.publish(&self.levels, &self.peaks);
+            }
+        }
+    }
+
+    fn analyze(&mut self) {
+        // The oldest frame sits at the write position.
+        let (newer, older) = self.input.split_at(self.input_position);
+        let ordered = older.iter().chain(newer);
+        for ((bin, &x), &w) in self.fft.iter_mut().zip(ordered).zip(&self.window) {
+            *bin = (x * w, 0.0);
+        }
+        fft_in_place(&mut self.fft, &self.twiddles);
+
+        // A full scale sine through the Hann window peaks at N/4.
+        let norm = 1.0 / ((FFT_SIZE / 4) as f32 * (FFT_SIZE / 4) as f32);
+        let hop_ms = (FFT_HOP * 1000) as f32 / self.sample_rate.max(1) as f32;
+        let decay_db = self.config.decay_db_per_s * hop_ms / 1000.0;
+        let floor_db = self.config.floor_db;
+        for band in 0..self.levels_db.len() {
+            let bins = self.band_edges[band]..self.band_edges[band + 1];
+            let power = self.fft[bins]
+                .iter()
+                .map(|&(re, im)| re * re + im * im)
+                .fold(0.0, f32::max)
+                * norm;
+            let db = (10.0 * libm::log10f(power.max(f32::MIN_POSITIVE))).clamp(floor_db, 0.0);
+
+            let level = &mut self.levels_db[band];
+            *level = db.max(*level - decay_db);
+            let (peak, age) = (&mut self.peaks_db[band], &mut self.peak_age_ms[band]);
+            if *level >= *peak {
+                *peak = *level;
+                *age = 0.0;
+            } else if *age < self.config.peak_hold_ms as f32 {
+                *age += hop_ms;
+            } else {
+                *peak = (*peak - decay_db).max(*level);
+            }
+            self.levels[band] = scale(*level, floor_db);
+            self.peaks[band] = scale(*peak, floor_db);
+        }
+    }
+}
+
+fn scale(db: f32, floor_db: f32) -> u8 {
+    (255.0 * (1.0 - db / floor_db)).clamp(0.0, 255.0) as u8
+}
+
+/// Iterative radix-2 decimation in time.
+fn fft_in_place(data: &mut [(f32, f32)], twiddles: &[(f32, f32)]) {
+    let n = data.len();
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            data.swap(i, j);
+        }
+    }
+    let mut size = 2;
+    while size <= n {
+        let stride = n / size;
+        for start in (0..n).step_by(size) {
+            for k in 0..size / 2 {
+                let (wr, wi) = twiddles[k * stride];
+                let (br, bi) = data[start + k + size / 2];
+                let t = (br * wr - bi * wi, br * wi + bi * wr);
+                let a = data[start + k];
+                data[start + k] = (a.0 + t.0, a.1 + t.1);
+                data[start + k + size / 2] = (a.0 - t.0, a.1 - t.1);
+            }
+        }
+        size *= 2;
+    }
+}
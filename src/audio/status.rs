@@ -0,0 +1,125 @@
+//! What the player is doing, for the display and any control interface.
+//!
+//! `player_task` publishes a [`PlaybackStatus`] to [`PLAYBACK_STATUS`] on every
+//! state or track change and every [`STATUS_PERIOD_MS`] of playback.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use heapless::String;
+
+#[cfg(target_os = "none")]
+use crate::audio::codec::codec::Decoder;
+
+pub const STATUS_PERIOD_MS: u64 = 250;
+/// Number of tasks that can hold a [`PLAYBACK_STATUS`] receiver at the same time.
+pub const STATUS_RECEIVERS: usize = 4;
+
+pub static PLAYBACK_STATUS: Watch<CriticalSectionRawMutex, PlaybackStatus, STATUS_RECEIVERS> =
+    Watch::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PlaybackState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Flac,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaybackStatus {
+    pub state: PlaybackState,
+    pub file_name: Option<&'static str>,
+    /// From the tags, truncated, empty if the track has no title.
+    pub title: String<64>,
+    /// Position currently coming out of the DAC.
+    pub elapsed_ms: u64,
+    pub total_ms: u64,
+    pub sample_rate: u32,
+    pub bits_per_sample: u8,
+    pub channels: u8,
+    pub codec: Option<Codec>,
+    /// Average over the whole file.
+    pub bitrate_kbps: u32,
+}
+
+impl PlaybackStatus {
+    #[cfg(target_os = "none")]
+    pub(crate) fn for_track(file_name: &'static str, file_len: usize, decoder: &Decoder) -> Self {
+        let sample_rate = decoder.sample_rate();
+        let total_ms = frames_to_ms(decoder.total_pcm_frames(), sample_rate);
+        let mut title = String::new();
+        for c in decoder.metadata().title_name.chars() {
+            if title.push(c).is_err() {
+                break;
+            }
+        }
+        Self {
+            state: PlaybackState::Paused,
+            file_name: Some(file_name),
+            title,
+            elapsed_ms: 0,
+            total_ms,
+            sample_rate,
+            bits_per_sample: decoder.bits_per_sample(),
+            channels: decoder.channels() as u8,
+            codec: Some(decoder.codec()),
+            bitrate_kbps: (file_len as u64 * 8 / total_ms.max(1)) as u32,
+        }
+    }
+
+    pub fn publish(&self) {
+        PLAYBACK_STATUS.sender().send(self.clone());
+    }
+
+    /// Publishes if the state changed or the position moved by at least
+    /// [`STATUS_PERIOD_MS`] since the last publish.
+    pub(crate) fn update(&mut self, state: PlaybackState, elapsed_frames: u64) {
+        let elapsed_ms = frames_to_ms(elapsed_frames, self.sample_rate);
+        if state != self.state || elapsed_ms.abs_diff(self.elapsed_ms) >= STATUS_PERIOD_MS {
+            self.state = state;
+            self.elapsed_ms = elapsed_ms;
+            self.publish();
+        }
+    }
+}
+
+fn frames_to_ms(frames: u64, sample_rate: u32) -> u64 {
+    frames * 1000 / sample_rate.max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_changes_are_published_even_when_the_position_stands_still() {
+        let mut receiver = PLAYBACK_STATUS.receiver().unwrap();
+        let mut status = PlaybackStatus {
+            state: PlaybackState::Playing,
+            sample_rate: 1_000,
+            ..PlaybackStatus::default()
+        };
+        status.update(PlaybackState::Playing, 100);
+        assert_eq!(receiver.try_changed(), None);
+        status.update(PlaybackState::Playing, 300);
+        assert_eq!(receiver.try_changed().map(|s| s.elapsed_ms), Some(300));
+
+        // Paused where it played up to.
+        status.update(PlaybackState::Paused, 300);
+        assert_eq!(
+            receiver.try_changed().map(|s| s.state),
+            Some(PlaybackState::Paused)
+        );
+        // A seek while stopped moves the position without playing anything.
+        status.update(PlaybackState::Stopped, 300);
+        status.update(PlaybackState::Stopped, 5_000);
+        let published = receiver.try_changed().unwrap();
+        assert_eq!(published.state, PlaybackState::Stopped);
+        assert_eq!(published.elapsed_ms, 5_000);
+    }
+}
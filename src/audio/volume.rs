@@ -0,0 +1,191 @@
+//! Output volume in dB, split over the TLV320DAC3100 volume stages.
+//!
+//! The DAC digital volume covers `-63.5..=+24` dB in 0.5 dB steps, anything
+//! quieter than that is taken off with the HP/SPK analog volume. Changes are
+//! walked towards the target a little on every call to [`Volume::next_hardware_step`]
+//! so the analog stage, which has no soft-stepping of its own, does not click.
+//! `player_task` takes a step every [`SOFT_STEP_INTERVAL_MS`] until the
+//! hardware gets there, whether or not anything is playing.
+
+use crate::audio::dsp::{Block, Processor, StreamFormat};
+use crate::audio::gain::{UNITY_Q16, apply_gain_q16, db_to_q16};
+
+pub const DAC_DIGITAL_MIN_DB: f32 = -63.5;
+pub const DAC_DIGITAL_MAX_DB: f32 = 24.0;
+/// The analog volume register is 0.5 dB/step up to roughly -36 dB and gets coarse after that.
+pub const ANALOG_MAX_ATTENUATION_STEP: u8 = 72;
+const ANALOG_DB_PER_STEP: f32 = 0.5;
+
+pub const MIN_VOLUME_DB: f32 =
+    DAC_DIGITAL_MIN_DB - ANALOG_MAX_ATTENUATION_STEP as f32 * ANALOG_DB_PER_STEP;
+pub const MAX_VOLUME_DB: f32 = DAC_DIGITAL_MAX_DB;
+pub const DEFAULT_VOLUME_DB: f32 = 8.0;
+/// Largest change applied to the hardware per step.
+const SOFT_STEP_DB: f32 = 1.0;
+/// Time between two soft-steps of the hardware volume.
+pub const SOFT_STEP_INTERVAL_MS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeCommand {
+    Set(f32),
+    Step(f32),
+    Mute(bool),
+    ToggleMute,
+    /// Gain of the software stage applied to the PCM, 0 dB bypasses it.
+    SetSoftwareGain(f32),
+}
+
+/// Register values for one hardware volume update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HardwareVolume {
+    pub digital_db: f32,
+    pub analog_attenuation_step: u8,
+    pub muted: bool,
+}
+
+impl HardwareVolume {
+    pub fn from_db(volume_db: f32, muted: bool) -> Self {
+        let volume_db = volume_db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+        let digital_db = round_to_half_db(volume_db.max(DAC_DIGITAL_MIN_DB));
+        let analog_attenuation_step = libm::roundf((digital_db - volume_db) / ANALOG_DB_PER_STEP)
+            .clamp(0.0, ANALOG_MAX_ATTENUATION_STEP as f32)
+            as u8;
+        Self {
+            digital_db,
+            analog_attenuation_step,
+            muted,
+        }
+    }
+}
+
+pub struct Volume {
+    target_db: f32,
+    current_db: f32,
+    muted: bool,
+    hardware_muted: bool,
+    software_gain_q16: i32,
+    clipped: u32,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self::new(DEFAULT_VOLUME_DB)
+    }
+}
+
+impl Volume {
+    /// `initial_db` is expected to already be programmed into the DAC.
+    pub fn new(initial_db: f32) -> Self {
+        let initial_db = initial_db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+        Self {
+            target_db: initial_db,
+            current_db: initial_db,
+            muted: false,
+            hardware_muted: false,
+            software_gain_q16: UNITY_Q16,
+            clipped: 0,
+        }
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        self.target_db
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Whether the hardware has yet to reach the target, see
+    /// [`Volume::next_hardware_step`].
+    pub fn is_stepping(&self) -> bool {
+        self.current_db != self.target_db || self.hardware_muted != self.muted
+    }
+
+    /// NaN levels are ignored, anything else is clamped to the volume range.
+    pub fn handle_command(&mut self, command: VolumeCommand) {
+        match command {
+            VolumeCommand::Set(db)
+            | VolumeCommand::Step(db)
+            | VolumeCommand::SetSoftwareGain(db)
+                if db.is_nan() => {}
+            VolumeCommand::Set(db) => self.target_db = db.clamp(MIN_VOLUME_DB, MAX_VOLUME_DB),
+            VolumeCommand::Step(db) => {
+                self.target_db = (self.target_db + db).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB)
+            }
+            VolumeCommand::Mute(muted) => self.muted = muted,
+            VolumeCommand::ToggleMute => self.muted = !self.muted,
+            VolumeCommand::SetSoftwareGain(db) => self.software_gain_q16 = db_to_q16(db),
+        }
+    }
+
+    /// Moves the hardware volume one soft-step closer to the target, returns
+    /// the registers to write or `None` if the hardware is already there.
+    pub fn next_hardware_step(&mut self) -> Option<HardwareVolume> {
+        if !self.is_stepping() {
+            return None;
+        }
+        let delta = self.target_db - self.current_db;
+        if delta.abs() <= SOFT_STEP_DB {
+            self.current_db = self.target_db;
+        } else {
+            self.current_db += SOFT_STEP_DB.copysign(delta);
+        }
+        self.hardware_muted = self.muted;
+        Some(HardwareVolume::from_db(
+            self.current_db,
+            self.hardware_muted,
+        ))
+    }
+}
+
+/// The software gain stage.
+impl Processor for Volume {
+    fn configure(&mut self, _format: StreamFormat) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, block: &mut Block<'_>) {
+        self.clipped += apply_gain_q16(block.samples_mut(), self.software_gain_q16);
+    }
+
+    fn take_clipped_samples(&mut self) -> u32 {
+        core::mem::take(&mut self.clipped)
+    }
+}
+
+fn round_to_half_db(db: f32) -> f32 {
+    libm::roundf(db * 2.0) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nan_levels_are_ignored() {
+        let mut volume = Volume::new(-20.0);
+        volume.handle_command(VolumeCommand::Set(f32::NAN));
+        volume.handle_command(VolumeCommand::Step(f32::NAN));
+        assert_eq!(volume.volume_db(), -20.0);
+        assert!(!volume.is_stepping());
+        volume.handle_command(VolumeCommand::Step(f32::INFINITY));
+        assert_eq!(volume.volume_db(), MAX_VOLUME_DB);
+    }
+
+    #[test]
+    fn the_hardware_steps_until_it_reaches_the_target() {
+        let mut volume = Volume::new(-20.0);
+        volume.handle_command(VolumeCommand::Set(-23.5));
+        let mut steps = 0;
+        while let Some(hardware_volume) = volume.next_hardware_step() {
+            steps += 1;
+            assert!(hardware_volume.digital_db >= -23.5);
+        }
+        assert_eq!(steps, 4);
+        assert!(!volume.is_stepping());
+
+        volume.handle_command(VolumeCommand::Mute(true));
+        assert!(volume.next_hardware_step().unwrap().muted);
+        assert_eq!(volume.next_hardware_step(), None);
+    }
+}
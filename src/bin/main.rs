@@ -7,11 +7,15 @@
 )]
 
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either3, select3};
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
 use esp_hal::gpio::{Level, Output, OutputConfig};
+use esp_hal::system::Stack;
 use esp_hal::timer::timg::TimerGroup;
+use esp_rtos::embassy::Executor;
+use static_cell::StaticCell;
 // use esp_println::{self as _, info};
 use defmt::info;
 use defmt_rtt as _;
@@ -20,9 +24,10 @@ use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
 // use mousefood::ratatui::Terminal;
 // use mousefood::*;
 
-use embedded_sdmmc::{LfnBuffer, Mode as FileMode, VolumeIdx};
+use embedded_sdmmc::VolumeIdx;
 
-use okja::audio::{AUDIO_DECODER, PLAY_PAUSE_STATE};
+use okja::audio::PLAYER_COMMANDS;
+use okja::audio::player::PlayerCommand;
 use okja::*;
 
 #[embassy_executor::task]
@@ -57,57 +62,117 @@ async fn display_task(mut display_object: DisplayObjectType) {
 
 #[embassy_executor::task]
 async fn sdcard_task(volume_manager: VolumeManagerType) {
-    // volume_manager.read(RawFile::ne(&self, other), buffer)
-    let volume_handle = volume_manager.open_volume(VolumeIdx(0)).unwrap();
-    let root_dir = volume_handle.open_root_dir().unwrap();
-    fn iter_dir(dir_input: DirectoryType) {
-        let mut storage = [0; 512];
-        let mut buf = LfnBuffer::new(&mut storage);
-        // info!("Listing {:?}", dir_input);
-        dir_input
-            .iterate_dir_lfn(&mut buf, |entry, buf| {
-                // info!("{:?}", entry.attributes);
-                if entry.attributes.is_directory() {
-                    // iter_dir();
-                }
-                if let Some(_buf) = buf {
-                    // info!(" {:?}", buf);
-                } else {
-                    // info!(".");
+    let volume_handle = match volume_manager.open_volume(VolumeIdx(0)) {
+        Ok(volume_handle) => volume_handle,
+        Err(e) => {
+            info!(
+                "Error opening the SD card volume: {}",
+                defmt::Debug2Format(&e)
+            );
+            play_embedded_tracks().await;
+            return;
+        }
+    };
+    let root_dir = match volume_handle.open_root_dir() {
+        Ok(root_dir) => root_dir,
+        Err(e) => {
+            info!(
+                "Error opening the SD card root: {}",
+                defmt::Debug2Format(&e)
+            );
+            play_embedded_tracks().await;
+            return;
+        }
+    };
+    audio::scanner::load_cache(&root_dir);
+
+    let session = audio::resume::load(&root_dir);
+    // Saves carry on numbering from the session on the card.
+    let last_sequence = session.as_ref().map_or(0, |session| session.sequence);
+    // The saved session, else the playlist, else the tracks built in.
+    let queued = match session {
+        Some(session) => {
+            let report = audio::resume::restore(&session, embedded_track, AUTO_RESUME).await;
+            info!(
+                "Restored {} saved tracks, {} missing",
+                report.queued, report.missing
+            );
+            report.queued
+        }
+        None => match audio::playlist::load(&root_dir, "", PLAYLIST_FILE_NAME) {
+            Some(entries) => {
+                let report = audio::playlist::enqueue(&entries, embedded_track).await;
+                info!(
+                    "Queued {} playlist entries, {} missing",
+                    report.queued, report.missing
+                );
+                if report.queued > 0 {
+                    PLAYER_COMMANDS.send(PlayerCommand::Play).await;
                 }
-            })
-            .unwrap();
+                report.queued
+            }
+            None => 0,
+        },
+    };
+    if queued == 0 {
+        play_embedded_tracks().await;
     }
 
-    iter_dir(root_dir.open_dir("/").unwrap());
-    // fn iter_dir(root_dir: Directory<'_, SdCard<ExclusiveDevice<Spi<'static, esp_hal::Blocking>, Output<'static>, Delay>, Delay>, DummyTimeSource, 4, 4, 1>)
-    //     dir_input
-    //         .open_dir(ShortFileName::create_from_str(&dir_input).unwrap())
-    //         .unwrap()
-    //         .iterate_dir_lfn(&mut buf, |file_name, buf| {
-    //             info!("{:?}", file_name.attributes);
-    //             if file_name.attributes.is_directory() {
-    //                 iter_dir(file_name);
-    //             }
-    //             if let Some(buf) = buf {
-    //                 info!(" {:?}", buf);
-    //             } else {
-    //                 info!(".");
-    //             }
-    //         })
-    //         .unwrap();
-    // };
-
-    let _flac_file = root_dir
-        .open_file_in_dir(
-            "Clipse, Pusha T, Malice - Inglorious Bastards.flac",
-            FileMode::ReadOnly,
+    let mut queue_writer = audio::playlist::QueueWriter::new().unwrap();
+    let mut resume_writer = audio::resume::ResumeWriter::new(last_sequence).unwrap();
+    loop {
+        let event = select3(
+            audio::scanner::LOUDNESS_CACHE_DIRTY.wait(),
+            queue_writer.wait_due(),
+            resume_writer.wait_due(),
         )
-        .unwrap();
+        .await;
+        match event {
+            Either3::First(()) => audio::scanner::save_cache(&root_dir),
+            Either3::Second(()) => queue_writer.save(&root_dir, QUEUE_FILE_NAME),
+            Either3::Third(()) => resume_writer.save(&root_dir),
+        }
+    }
 }
 
 extern crate alloc;
 
+/// Played when the card has nothing to queue. Also the only tracks playlists
+/// and saved sessions can name: audio files on the card cannot be played yet,
+/// see `audio::playlist`.
+static EMBEDDED_TRACKS: [audio::FileInfo; 1] = [audio::FileInfo {
+    file_name: "stereo.flac",
+    file_bytes: include_bytes!("../../assets/stereo.flac"),
+}];
+/// Queued from the card root at startup when there is no saved session.
+const PLAYLIST_FILE_NAME: &str = "PLAYLIST.M3U";
+/// Where the queue is saved once it settles after a change.
+const QUEUE_FILE_NAME: &str = "QUEUE.M3U";
+/// Carry on playing a restored session that was playing at power-off,
+/// instead of waiting paused for Play.
+const AUTO_RESUME: bool = true;
+
+/// Resolves playlist and session entries, only against [`EMBEDDED_TRACKS`].
+fn embedded_track(path: &str) -> Option<audio::FileInfo> {
+    EMBEDDED_TRACKS
+        .iter()
+        .find(|track| track.file_name.eq_ignore_ascii_case(path))
+        .copied()
+}
+
+async fn play_embedded_tracks() {
+    for file_info in EMBEDDED_TRACKS {
+        PLAYER_COMMANDS
+            .send(PlayerCommand::Enqueue(file_info.into()))
+            .await;
+    }
+    PLAYER_COMMANDS.send(PlayerCommand::Play).await;
+}
+
+const APP_CORE_STACK_SIZE: usize = 16 * 1024;
+static APP_CORE_STACK: StaticCell<Stack<APP_CORE_STACK_SIZE>> = StaticCell::new();
+static APP_CORE_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -142,24 +207,21 @@ async fn main(spawner: Spawner) {
     // spawner
     //     .spawn(display_task(app_resource.display_object))
     //     .unwrap();
-    // spawner
-    //     .spawn(sdcard_task(app_resource.volume_manager))
-    //     .unwrap();
-    spawner.spawn(okja::audio::player_task(app_resource.dac_peripherals).unwrap());
-
-    Timer::after(Duration::from_secs(1)).await;
-    static AUDIO_FILENAME: &str = "stereo.flac";
-    static FLAC_AUDIO: &[u8] = include_bytes!("../../assets/stereo.flac");
-    let file_info = audio::FileInfo {
-        file_name: AUDIO_FILENAME,
-        file_bytes: FLAC_AUDIO,
-    };
-    loop {
-        AUDIO_DECODER.reset();
-        AUDIO_DECODER.signal(file_info);
-        PLAY_PAUSE_STATE.signal(audio::PlayPauseState::Play);
-        Timer::after(Duration::from_secs(10)).await;
-        PLAY_PAUSE_STATE.signal(audio::PlayPauseState::Pause);
-        Timer::after(Duration::from_secs(1)).await;
-    }
+    spawner.spawn(sdcard_task(app_resource.volume_manager).unwrap());
+    let DACResources { i2s, tlv_obj } = app_resource.dac_peripherals;
+    spawner.spawn(okja::audio::output_task(i2s).unwrap());
+    // Decoding gets the APP core to itself, so nothing else can make it late.
+    esp_rtos::start_second_core(
+        peripherals.CPU_CTRL,
+        sw_interrupt.software_interrupt1,
+        APP_CORE_STACK.init(Stack::new()),
+        move || {
+            let executor = APP_CORE_EXECUTOR.init(Executor::new());
+            executor.run(|spawner| {
+                spawner.spawn(okja::audio::player_task(tlv_obj).unwrap());
+            });
+        },
+    );
+    spawner.spawn(okja::audio::scanner::loudness_scan_task().unwrap());
+    spawner.spawn(okja::audio::health::health_log_task().unwrap());
 }
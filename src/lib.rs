@@ -1,4 +1,6 @@
 #![no_std]
+extern crate alloc;
+
 pub mod audio;
 
 use embassy_time::Delay;
@@ -88,12 +90,17 @@ impl embedded_sdmmc::TimeSource for DummyTimeSource {
 
 // Init_done
 pub struct DACResources {
+    pub i2s: I2SResources,
+    pub tlv_obj: TLV320DAC3100<I2c<'static, Blocking>>,
+}
+
+/// What `output_task` needs to drive the I2S output.
+pub struct I2SResources {
     i2s_module: I2S0<'static>,
     i2s_dma: DMA_CH1<'static>,
     i2s_dout: GPIO17<'static>, // DATA - I2S data
     i2s_ws: GPIO18<'static>,   // LRCLOCK - Word select
     i2s_bclk: GPIO44<'static>, // BITCLOCK - I2S clock
-    tlv_obj: TLV320DAC3100<I2c<'static, Blocking>>,
 }
 
 pub type VolumeManagerType = embedded_sdmmc::VolumeManager<